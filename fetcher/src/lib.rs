@@ -1,8 +1,28 @@
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::Receiver;
 use tracing::info;
-use types::{EdgeError, EdgeResult, EdgeToken};
+use types::{ApiTokenType, EdgeError, EdgeResult, EdgeToken};
 use unleash_types::client_features::ClientFeatures;
 
+/// A token upstream Unleash knows about, as returned by its edge token validation endpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValidatedToken {
+    pub token: String,
+    #[serde(rename = "type")]
+    pub token_type: ApiTokenType,
+    pub projects: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ValidationRequest {
+    tokens: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ValidationResponse {
+    tokens: Vec<ValidatedToken>,
+}
+
 pub async fn fetch_client_features(
     client: reqwest::Client,
     unleash_url: String,
@@ -13,6 +33,7 @@ pub async fn fetch_client_features(
         .header("Authorization", unleash_token.clone())
         .send()
         .await
+        .and_then(|response| response.error_for_status())
         .map_err(|_| EdgeError::UnleashApiError)?;
     info!("Successfully fetched data for token: {}", unleash_token);
     let data = result
@@ -22,3 +43,24 @@ pub async fn fetch_client_features(
     info!("converted to json: {:#?}", data);
     data
 }
+
+/// Asks upstream which of the tokens are valid. Tokens upstream doesn't know are left out of
+/// the result
+pub async fn validate_tokens(
+    client: reqwest::Client,
+    validation_url: String,
+    tokens: Vec<String>,
+) -> EdgeResult<Vec<ValidatedToken>> {
+    let response = client
+        .post(validation_url)
+        .json(&ValidationRequest { tokens })
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|_| EdgeError::UnleashApiError)?;
+    response
+        .json::<ValidationResponse>()
+        .await
+        .map(|validated| validated.tokens)
+        .map_err(|_| EdgeError::UnleashJsonError)
+}
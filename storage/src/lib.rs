@@ -21,6 +21,7 @@ pub struct Status {
 pub struct CachedData {
     pub status: Status,
     pub client_features: ClientFeatures,
+    pub etag: Option<String>,
 }
 
 impl Default for CachedData {
@@ -33,6 +34,7 @@ impl Default for CachedData {
                 segments: None,
                 query: None,
             },
+            etag: None,
         }
     }
 }
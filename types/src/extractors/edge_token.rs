@@ -1,15 +1,19 @@
-use crate::{ApiToken, EdgeError, EdgeResult, EdgeToken};
+use crate::{ApiToken, ApiTokenType, EdgeError, EdgeResult, EdgeToken};
 use actix_utils::future::{ready, Ready};
 use actix_web::dev::Payload;
 use actix_web::http::header::HeaderValue;
-use actix_web::{web, FromRequest, HttpRequest};
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
 use std::str::FromStr;
 
 impl FromRequest for EdgeToken {
     type Error = EdgeError;
     type Future = Ready<Result<Self, Self::Error>>;
 
+    /// Prefers the token validated by upstream, when the request went through validation
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        if let Some(validated) = req.extensions().get::<EdgeToken>() {
+            return ready(Ok(validated.clone()));
+        }
         let value: Option<EdgeResult<EdgeToken>> = req
             .headers()
             .get("Authorization")
@@ -25,47 +29,228 @@ impl TryFrom<HeaderValue> for EdgeToken {
         value
             .to_str()
             .map_err(|_| EdgeError::InvalidHeaderValue)
-            .and_then(|t| EdgeToken::from_str(t))
+            .and_then(EdgeToken::from_str)
     }
 }
 
+/// Prefix some clients put in front of the token in the Authorization header
+const BEARER_PREFIX: &str = "bearer ";
+
+/// Parses an Unleash API token, `<project>:<environment>.<secret>`. The project is a single
+/// project, `*` for every project or `[]` for a token with several projects, which are only
+/// known upstream. `*:*.<secret>` is an admin token
 impl FromStr for EdgeToken {
     type Err = EdgeError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let token_parts: Vec<String> = s
-            .clone()
-            .split(":")
-            .take(2)
-            .map(|s| s.to_string())
-            .collect();
-        let token_projects = if let Some(projects) = token_parts.get(0) {
-            if projects == "[]" {
-                vec![]
-            } else {
-                vec![projects.clone()]
+        let s = s.trim();
+        let token = match s.get(..BEARER_PREFIX.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(BEARER_PREFIX) => {
+                s[BEARER_PREFIX.len()..].trim_start()
             }
-        } else {
-            return Err(EdgeError::AuthorizationDenied);
+            _ => s,
         };
-        if let Some(env_and_key) = token_parts.get(1) {
-            let e_a_k: Vec<String> = env_and_key
-                .split(".")
-                .take(2)
-                .map(|s| s.to_string())
-                .collect();
-            if e_a_k.len() != 2 {
-                return Err(EdgeError::AuthorizationDenied);
-            }
-            Ok(EdgeToken {
-                token: e_a_k.get(1).unwrap().clone(),
-                environment: e_a_k.get(0).unwrap().clone(),
-                projects: token_projects,
-                token_type: ApiToken::Undecided,
-                dynamic: false,
-            })
-        } else {
-            return Err(EdgeError::AuthorizationDenied);
+        if token.is_empty() || token.eq_ignore_ascii_case(BEARER_PREFIX.trim_end()) {
+            return Err(EdgeError::EmptyToken);
+        }
+        if token.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(EdgeError::InvalidTokenCharacters);
+        }
+        let (project, env_and_secret) = token
+            .split_once(':')
+            .filter(|(project, _)| !project.is_empty())
+            .ok_or(EdgeError::TokenWithoutProject)?;
+        // Secrets never contain dots, environment names might
+        let (environment, secret) = env_and_secret
+            .rsplit_once('.')
+            .filter(|(environment, _)| !environment.is_empty())
+            .ok_or(EdgeError::TokenWithoutEnvironment)?;
+        if secret.is_empty() {
+            return Err(EdgeError::TokenWithoutSecret);
+        }
+        if env_and_secret.contains(':') || (project.contains(['[', ']']) && project != "[]") {
+            return Err(EdgeError::InvalidTokenCharacters);
         }
+        let token_type = match (project, environment) {
+            ("*", "*") => ApiToken::Decided(ApiTokenType::Admin),
+            (_, "*") => return Err(EdgeError::InvalidTokenEnvironment),
+            _ => ApiToken::Undecided,
+        };
+        let projects = match project {
+            "[]" => vec![],
+            project => vec![project.to_string()],
+        };
+        Ok(EdgeToken {
+            token: token.to_string(),
+            environment: environment.to_string(),
+            projects,
+            token_type,
+            dynamic: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(token: &str) -> EdgeResult<EdgeToken> {
+        EdgeToken::from_str(token)
+    }
+
+    #[test]
+    fn single_project_token() {
+        let token =
+            parse("demo-app:production.614a75cf68bef8703aa1bd8304938a81ec871f86ea40c975468eabd6")
+                .unwrap();
+        assert_eq!(
+            token.token,
+            "demo-app:production.614a75cf68bef8703aa1bd8304938a81ec871f86ea40c975468eabd6"
+        );
+        assert_eq!(token.environment, "production");
+        assert_eq!(token.projects, vec!["demo-app".to_string()]);
+        assert_eq!(token.token_type, ApiToken::Undecided);
+        assert!(!token.dynamic);
+    }
+
+    #[test]
+    fn wildcard_project_token() {
+        let token = parse("*:development.secret").unwrap();
+        assert_eq!(token.environment, "development");
+        assert_eq!(token.projects, vec!["*".to_string()]);
+        assert_eq!(token.token_type, ApiToken::Undecided);
+    }
+
+    #[test]
+    fn multi_project_token_has_no_projects_until_validated() {
+        let token = parse("[]:development.secret").unwrap();
+        assert_eq!(token.environment, "development");
+        assert!(token.projects.is_empty());
+        assert_eq!(token.token_type, ApiToken::Undecided);
+    }
+
+    #[test]
+    fn admin_token() {
+        let token = parse("*:*.secret").unwrap();
+        assert_eq!(token.environment, "*");
+        assert_eq!(token.projects, vec!["*".to_string()]);
+        assert_eq!(token.token_type, ApiToken::Decided(ApiTokenType::Admin));
+    }
+
+    #[test]
+    fn bearer_prefix_is_stripped() {
+        for header in [
+            "Bearer *:development.secret",
+            "bearer *:development.secret",
+            "BEARER   *:development.secret",
+        ] {
+            let token = parse(header).unwrap();
+            assert_eq!(token.token, "*:development.secret");
+            assert_eq!(token.environment, "development");
+        }
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() {
+        let token = parse("  default:development.secret\n").unwrap();
+        assert_eq!(token.token, "default:development.secret");
+    }
+
+    #[test]
+    fn environment_may_contain_dots() {
+        let token = parse("default:eu.production.secret").unwrap();
+        assert_eq!(token.environment, "eu.production");
+        assert_eq!(token.token, "default:eu.production.secret");
+    }
+
+    #[test]
+    fn empty_token_is_rejected() {
+        assert_eq!(parse(""), Err(EdgeError::EmptyToken));
+        assert_eq!(parse("   "), Err(EdgeError::EmptyToken));
+        assert_eq!(parse("Bearer "), Err(EdgeError::EmptyToken));
+        assert_eq!(parse("Bearer"), Err(EdgeError::EmptyToken));
+    }
+
+    #[test]
+    fn token_without_project_is_rejected() {
+        assert_eq!(
+            parse("development.secret"),
+            Err(EdgeError::TokenWithoutProject)
+        );
+        assert_eq!(
+            parse(":development.secret"),
+            Err(EdgeError::TokenWithoutProject)
+        );
+        assert_eq!(parse("secret"), Err(EdgeError::TokenWithoutProject));
+    }
+
+    #[test]
+    fn token_without_environment_is_rejected() {
+        assert_eq!(
+            parse("default:secret"),
+            Err(EdgeError::TokenWithoutEnvironment)
+        );
+        assert_eq!(
+            parse("default:.secret"),
+            Err(EdgeError::TokenWithoutEnvironment)
+        );
+        assert_eq!(parse("default:"), Err(EdgeError::TokenWithoutEnvironment));
+    }
+
+    #[test]
+    fn token_without_secret_is_rejected() {
+        assert_eq!(
+            parse("default:development."),
+            Err(EdgeError::TokenWithoutSecret)
+        );
+        assert_eq!(parse("*:*."), Err(EdgeError::TokenWithoutSecret));
+    }
+
+    #[test]
+    fn token_with_invalid_characters_is_rejected() {
+        assert_eq!(
+            parse("default:devel opment.secret"),
+            Err(EdgeError::InvalidTokenCharacters)
+        );
+        assert_eq!(
+            parse("default:development.sec\tret"),
+            Err(EdgeError::InvalidTokenCharacters)
+        );
+        assert_eq!(
+            parse("default:development:other.secret"),
+            Err(EdgeError::InvalidTokenCharacters)
+        );
+        assert_eq!(
+            parse("[default]:development.secret"),
+            Err(EdgeError::InvalidTokenCharacters)
+        );
+    }
+
+    #[test]
+    fn wildcard_environment_is_only_valid_for_admin_tokens() {
+        assert_eq!(
+            parse("default:*.secret"),
+            Err(EdgeError::InvalidTokenEnvironment)
+        );
+        assert_eq!(
+            parse("[]:*.secret"),
+            Err(EdgeError::InvalidTokenEnvironment)
+        );
+    }
+
+    #[test]
+    fn header_value_is_parsed() {
+        let token =
+            EdgeToken::try_from(HeaderValue::from_static("Bearer *:development.secret")).unwrap();
+        assert_eq!(token.token, "*:development.secret");
+    }
+
+    #[test]
+    fn non_ascii_header_value_is_rejected() {
+        let header = HeaderValue::from_bytes(b"*:development.s\xe9cret").unwrap();
+        assert_eq!(
+            EdgeToken::try_from(header),
+            Err(EdgeError::InvalidHeaderValue)
+        );
     }
 }
@@ -1 +1,2 @@
 pub mod edge_token;
+pub mod token_guards;
@@ -0,0 +1,147 @@
+use crate::{ApiToken, ApiTokenType, EdgeError, EdgeResult, EdgeToken};
+use actix_utils::future::{ready, Ready};
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+
+/// A token allowed to evaluate toggles for frontend applications
+#[derive(Debug, Clone)]
+pub struct FrontendToken(pub EdgeToken);
+
+/// A token allowed to fetch feature configurations for server-side SDKs
+#[derive(Debug, Clone)]
+pub struct ClientToken(pub EdgeToken);
+
+/// A client or frontend token. Admin tokens are never accepted on public routes
+#[derive(Debug, Clone)]
+pub struct PublicToken(pub EdgeToken);
+
+fn guard(
+    req: &HttpRequest,
+    payload: &mut Payload,
+    allowed: &[ApiTokenType],
+    surface: &str,
+) -> EdgeResult<EdgeToken> {
+    let token = EdgeToken::from_request(req, payload).into_inner()?;
+    match &token.token_type {
+        ApiToken::Decided(token_type) if allowed.contains(token_type) => Ok(token),
+        ApiToken::Decided(token_type) => Err(EdgeError::AuthorizationDenied(format!(
+            "{:?} tokens can not be used for {}",
+            token_type, surface
+        ))),
+        ApiToken::Undecided => Err(EdgeError::AuthorizationDenied(format!(
+            "the token type must be known to use {}",
+            surface
+        ))),
+    }
+}
+
+impl FromRequest for FrontendToken {
+    type Error = EdgeError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        ready(
+            guard(
+                req,
+                payload,
+                &[ApiTokenType::Frontend],
+                "frontend evaluation",
+            )
+            .map(FrontendToken),
+        )
+    }
+}
+
+impl FromRequest for ClientToken {
+    type Error = EdgeError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        ready(guard(req, payload, &[ApiTokenType::Client], "client features").map(ClientToken))
+    }
+}
+
+impl FromRequest for PublicToken {
+    type Error = EdgeError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        ready(
+            guard(
+                req,
+                payload,
+                &[ApiTokenType::Client, ApiTokenType::Frontend],
+                "public routes",
+            )
+            .map(PublicToken),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use actix_web::test::TestRequest;
+    use actix_web::{HttpMessage, ResponseError};
+
+    fn validated(token_type: ApiToken) -> HttpRequest {
+        let req = TestRequest::default().to_http_request();
+        req.extensions_mut().insert(EdgeToken {
+            token: "*:development.secret".to_string(),
+            environment: "development".to_string(),
+            projects: vec!["*".to_string()],
+            token_type,
+            dynamic: false,
+        });
+        req
+    }
+
+    fn extract<T: FromRequest<Error = EdgeError>>(token_type: ApiToken) -> Result<T, EdgeError> {
+        let request = validated(token_type);
+        actix_web::rt::System::new().block_on(T::from_request(&request, &mut Payload::None))
+    }
+
+    fn body(error: EdgeError) -> String {
+        let response = error.error_response();
+        assert_eq!(response.status(), 403);
+        let bytes = response.into_body().try_into_bytes().unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    const ADMIN: ApiToken = ApiToken::Decided(ApiTokenType::Admin);
+    const CLIENT: ApiToken = ApiToken::Decided(ApiTokenType::Client);
+    const FRONTEND: ApiToken = ApiToken::Decided(ApiTokenType::Frontend);
+
+    #[test]
+    fn admin_tokens_are_refused_by_every_guard() {
+        assert!(extract::<FrontendToken>(ADMIN).is_err());
+        assert!(extract::<ClientToken>(ADMIN).is_err());
+        assert!(extract::<PublicToken>(ADMIN).is_err());
+    }
+
+    #[test]
+    fn undecided_tokens_are_refused_by_every_guard() {
+        assert!(extract::<FrontendToken>(ApiToken::Undecided).is_err());
+        assert!(extract::<ClientToken>(ApiToken::Undecided).is_err());
+        assert!(extract::<PublicToken>(ApiToken::Undecided).is_err());
+    }
+
+    #[test]
+    fn each_guard_only_accepts_its_own_token_types() {
+        assert!(extract::<FrontendToken>(FRONTEND).is_ok());
+        assert!(extract::<FrontendToken>(CLIENT).is_err());
+        assert!(extract::<ClientToken>(CLIENT).is_ok());
+        assert!(extract::<ClientToken>(FRONTEND).is_err());
+        assert!(extract::<PublicToken>(CLIENT).is_ok());
+        assert!(extract::<PublicToken>(FRONTEND).is_ok());
+    }
+
+    #[test]
+    fn refusals_are_forbidden_with_the_reason_in_the_body() {
+        let wrong_type = extract::<FrontendToken>(CLIENT).unwrap_err();
+        assert!(body(wrong_type).contains("Client tokens can not be used for frontend evaluation"));
+        let undecided = extract::<ClientToken>(ApiToken::Undecided).unwrap_err();
+        assert!(body(undecided).contains("the token type must be known to use client features"));
+    }
+}
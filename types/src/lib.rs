@@ -15,14 +15,32 @@ pub enum EdgeError {
     CouldNotBind,
     CouldNotParseQuery,
     InvalidHeaderValue,
-    AuthorizationDenied,
+    AuthorizationDenied(String),
     NoToken,
     NoHttpClient,
+    FeatureNotFound,
+    NoCachedFeatures,
+    CouldNotSerialize,
+    ConnectionClosed,
+    CouldNotCompress,
+    EnvironmentMismatch,
+    EmptyToken,
+    InvalidTokenCharacters,
+    TokenWithoutProject,
+    TokenWithoutEnvironment,
+    TokenWithoutSecret,
+    InvalidTokenEnvironment,
 }
 
 impl Display for EdgeError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&*self.to_string())
+        match self {
+            EdgeError::AuthorizationDenied(reason) => write!(f, "Authorization denied: {}", reason),
+            EdgeError::EnvironmentMismatch => f.write_str(
+                "The environment in the context does not match the environment of the token",
+            ),
+            other => write!(f, "{:?}", other),
+        }
     }
 }
 
@@ -37,14 +55,29 @@ impl ResponseError for EdgeError {
             Self::CouldNotBind => StatusCode::INTERNAL_SERVER_ERROR,
             Self::CouldNotParseQuery => StatusCode::BAD_REQUEST,
             Self::InvalidHeaderValue => StatusCode::BAD_REQUEST,
-            Self::AuthorizationDenied => StatusCode::FORBIDDEN,
+            Self::AuthorizationDenied(_) => StatusCode::FORBIDDEN,
             Self::NoToken => StatusCode::UNAUTHORIZED,
             Self::NoHttpClient => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::FeatureNotFound => StatusCode::NOT_FOUND,
+            Self::NoCachedFeatures => StatusCode::SERVICE_UNAVAILABLE,
+            Self::CouldNotSerialize => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::ConnectionClosed => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::CouldNotCompress => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::EnvironmentMismatch => StatusCode::FORBIDDEN,
+            Self::EmptyToken => StatusCode::UNAUTHORIZED,
+            Self::InvalidTokenCharacters => StatusCode::UNAUTHORIZED,
+            Self::TokenWithoutProject => StatusCode::UNAUTHORIZED,
+            Self::TokenWithoutEnvironment => StatusCode::UNAUTHORIZED,
+            Self::TokenWithoutSecret => StatusCode::UNAUTHORIZED,
+            Self::InvalidTokenEnvironment => StatusCode::UNAUTHORIZED,
         }
     }
 
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        match self.status_code() {
+        match self {
+            EdgeError::AuthorizationDenied(_) | EdgeError::EnvironmentMismatch => {
+                HttpResponse::build(self.status_code()).body(self.to_string())
+            }
             _ => HttpResponse::build(self.status_code()).finish(),
         }
     }
@@ -57,6 +90,7 @@ pub enum ApiToken {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 pub enum ApiTokenType {
     Client,
     Admin,
@@ -71,3 +105,12 @@ pub struct EdgeToken {
     pub token_type: ApiToken,
     pub dynamic: bool,
 }
+
+impl EdgeToken {
+    /// Whether the token may see features in the given project. Features without a project
+    /// belong to the default project, and a `*` project grants access to every project
+    pub fn has_access_to_project(&self, project: Option<&str>) -> bool {
+        let project = project.unwrap_or("default");
+        self.projects.iter().any(|p| p == "*" || p == project)
+    }
+}
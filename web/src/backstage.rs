@@ -1,3 +1,4 @@
+use crate::token_validator::TokenValidator;
 use crate::EdgeJsonResult;
 use actix_web::web::Json;
 use actix_web::{get, web, HttpResponse, Responder};
@@ -14,7 +15,9 @@ async fn health() -> impl Responder {
 async fn register_token(
     token: EdgeToken,
     full_state: web::Data<Arc<FullState>>,
+    validator: web::Data<Arc<TokenValidator>>,
 ) -> EdgeJsonResult<()> {
+    let token = validator.validate(token).await?;
     full_state.data.insert(token.token, CachedData::default());
     Ok(Json(()))
 }
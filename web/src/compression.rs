@@ -0,0 +1,196 @@
+use crate::EdgeConfig;
+use actix_web::http::header::ACCEPT_ENCODING;
+use actix_web::web::{Bytes, Data};
+use actix_web::HttpRequest;
+use dashmap::DashMap;
+use flate2::write::GzEncoder;
+use std::io::{Cursor, Write};
+use std::sync::Arc;
+use types::{EdgeError, EdgeResult};
+
+/// Brotli's default of 11 is meant for static assets; 5 compresses almost as well at a
+/// fraction of the CPU, which matters for payloads that are compressed per request
+const BROTLI_QUALITY: i32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn compress(&self, bytes: &[u8]) -> EdgeResult<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let params = brotli::enc::BrotliEncoderParams {
+                    quality: BROTLI_QUALITY,
+                    ..Default::default()
+                };
+                let mut compressed = Vec::new();
+                brotli::enc::BrotliCompress(&mut Cursor::new(bytes), &mut compressed, &params)
+                    .map_err(|_| EdgeError::CouldNotCompress)?;
+                Ok(compressed)
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(bytes)
+                    .and_then(|_| encoder.finish())
+                    .map_err(|_| EdgeError::CouldNotCompress)
+            }
+        }
+    }
+}
+
+/// Compressed copies of each token's features payload, tagged with the ETag of the data they
+/// were made from so they are recompressed only after the refresher installs new features
+#[derive(Debug, Default)]
+pub struct CompressedPayload {
+    etag: String,
+    brotli: Option<Bytes>,
+    gzip: Option<Bytes>,
+}
+
+impl CompressedPayload {
+    fn get(&self, encoding: Encoding) -> Option<&Bytes> {
+        match encoding {
+            Encoding::Brotli => self.brotli.as_ref(),
+            Encoding::Gzip => self.gzip.as_ref(),
+        }
+    }
+
+    fn set(&mut self, encoding: Encoding, bytes: Bytes) {
+        match encoding {
+            Encoding::Brotli => self.brotli = Some(bytes),
+            Encoding::Gzip => self.gzip = Some(bytes),
+        }
+    }
+}
+
+pub type CompressionCache = DashMap<String, CompressedPayload>;
+
+/// Picks the encoding the client prefers from its Accept-Encoding header, favouring brotli
+/// over gzip when both are equally acceptable
+pub fn negotiate(req: &HttpRequest) -> Option<Encoding> {
+    let accepted = req.headers().get(ACCEPT_ENCODING)?.to_str().ok()?;
+    accepted
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let encoding = match parts.next()?.trim().to_ascii_lowercase().as_str() {
+                "br" => Encoding::Brotli,
+                "gzip" | "x-gzip" => Encoding::Gzip,
+                _ => return None,
+            };
+            let quality = parts
+                .find_map(|parameter| parameter.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (quality > 0.0).then_some((encoding, quality))
+        })
+        .max_by(|(a, a_quality), (b, b_quality)| {
+            a_quality
+                .total_cmp(b_quality)
+                .then_with(|| (*a == Encoding::Brotli).cmp(&(*b == Encoding::Brotli)))
+        })
+        .map(|(encoding, _)| encoding)
+}
+
+/// Returns an already compressed copy of the token's payload if one exists for this ETag
+pub fn cached(req: &HttpRequest, token: &str, etag: &str) -> Option<(Encoding, Bytes)> {
+    let encoding = negotiate(req)?;
+    let cache = req.app_data::<Data<Arc<CompressionCache>>>()?;
+    let payload = cache.get(token)?;
+    if payload.etag != etag {
+        return None;
+    }
+    payload.get(encoding).map(|bytes| (encoding, bytes.clone()))
+}
+
+/// Compresses the body when the client accepts it and it is at least the configured minimum
+/// size. Passing the token and ETag stores the result for the next request for the same data
+pub fn encode(
+    req: &HttpRequest,
+    body: Vec<u8>,
+    cache_key: Option<(&str, &str)>,
+) -> EdgeResult<(Option<Encoding>, Bytes)> {
+    let min_size = req
+        .app_data::<Data<EdgeConfig>>()
+        .map(|config| config.compression_min_size)
+        .unwrap_or(usize::MAX);
+    let encoding = match negotiate(req) {
+        Some(encoding) if body.len() >= min_size => encoding,
+        _ => return Ok((None, Bytes::from(body))),
+    };
+    let compressed = Bytes::from(encoding.compress(&body)?);
+    if let Some((token, etag)) = cache_key {
+        if let Some(cache) = req.app_data::<Data<Arc<CompressionCache>>>() {
+            let mut payload = cache.entry(token.to_string()).or_default();
+            if payload.etag != etag {
+                *payload = CompressedPayload {
+                    etag: etag.to_string(),
+                    ..Default::default()
+                };
+            }
+            payload.set(encoding, compressed.clone());
+        }
+    }
+    Ok((Some(encoding), compressed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn negotiate_for(accept_encoding: &str) -> Option<Encoding> {
+        let req = TestRequest::default()
+            .insert_header((ACCEPT_ENCODING, accept_encoding))
+            .to_http_request();
+        negotiate(&req)
+    }
+
+    #[test]
+    fn nothing_is_negotiated_without_a_supported_encoding() {
+        assert_eq!(negotiate(&TestRequest::default().to_http_request()), None);
+        assert_eq!(negotiate_for("identity, deflate"), None);
+    }
+
+    #[test]
+    fn a_zero_quality_refuses_the_encoding() {
+        assert_eq!(negotiate_for("gzip;q=0"), None);
+        assert_eq!(negotiate_for("br;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate_for("br, gzip; q=0.0"), Some(Encoding::Brotli));
+    }
+
+    #[test]
+    fn the_highest_quality_wins() {
+        assert_eq!(negotiate_for("gzip;q=0.8, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate_for("gzip;q=0.5, br;q=0.8"),
+            Some(Encoding::Brotli)
+        );
+    }
+
+    #[test]
+    fn brotli_wins_ties() {
+        assert_eq!(negotiate_for("gzip, br"), Some(Encoding::Brotli));
+        assert_eq!(
+            negotiate_for("gzip;q=0.7, br;q=0.7"),
+            Some(Encoding::Brotli)
+        );
+    }
+
+    #[test]
+    fn x_gzip_and_any_case_are_gzip() {
+        assert_eq!(negotiate_for("x-gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate_for("GZIP"), Some(Encoding::Gzip));
+    }
+}
@@ -0,0 +1,279 @@
+use sdk_core::state::InnerContext;
+use serde::Deserialize;
+use std::collections::HashMap;
+use types::{EdgeError, EdgeResult, EdgeToken};
+
+/// Rules that apply to every token, before the token's own rules
+const ANY_TOKEN: &str = "*";
+
+/// Context values a rule sets. Only the fields that are given are applied
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContextValues {
+    user_id: Option<String>,
+    session_id: Option<String>,
+    environment: Option<String>,
+    app_name: Option<String>,
+    #[serde(default)]
+    properties: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ContextRule {
+    /// Fill in fields the client left out
+    #[serde(default)]
+    defaults: ContextValues,
+    /// Replace fields whatever the client sent
+    #[serde(default)]
+    overrides: ContextValues,
+}
+
+/// Context rules keyed by token, with `*` matching any token. Read from a JSON file such as
+/// `{"*": {"defaults": {"appName": "web"}}, "<token>": {"overrides": {"environment": "prod"}}}`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ContextRules(HashMap<String, ContextRule>);
+
+impl ContextRules {
+    /// Used by clap to load the file given with `--context-rules`
+    pub fn from_file(path: &str) -> Result<ContextRules, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read context rules from {}: {}", path, e))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("Could not parse context rules in {}: {}", path, e))
+    }
+
+    /// Applies the rules for the token to the client's context. A token's own defaults take
+    /// precedence over the `*` defaults, and its own overrides are applied after the `*` ones
+    pub fn apply(&self, token: &EdgeToken, unleash_context: &mut InnerContext) {
+        let any_token = self.0.get(ANY_TOKEN);
+        let this_token = self.0.get(&token.token);
+        for rule in [this_token, any_token].into_iter().flatten() {
+            fill(&rule.defaults, unleash_context);
+        }
+        for rule in [any_token, this_token].into_iter().flatten() {
+            replace(&rule.overrides, unleash_context);
+        }
+    }
+}
+
+fn fill(defaults: &ContextValues, unleash_context: &mut InnerContext) {
+    let fill_field = |field: &mut Option<String>, value: &Option<String>| {
+        if field.is_none() {
+            *field = value.clone();
+        }
+    };
+    fill_field(&mut unleash_context.user_id, &defaults.user_id);
+    fill_field(&mut unleash_context.session_id, &defaults.session_id);
+    fill_field(&mut unleash_context.environment, &defaults.environment);
+    fill_field(&mut unleash_context.app_name, &defaults.app_name);
+    if !defaults.properties.is_empty() {
+        let properties = unleash_context.properties.get_or_insert_with(HashMap::new);
+        for (name, value) in &defaults.properties {
+            properties
+                .entry(name.clone())
+                .or_insert_with(|| value.clone());
+        }
+    }
+}
+
+fn replace(overrides: &ContextValues, unleash_context: &mut InnerContext) {
+    let replace_field = |field: &mut Option<String>, value: &Option<String>| {
+        if value.is_some() {
+            *field = value.clone();
+        }
+    };
+    replace_field(&mut unleash_context.user_id, &overrides.user_id);
+    replace_field(&mut unleash_context.session_id, &overrides.session_id);
+    replace_field(&mut unleash_context.environment, &overrides.environment);
+    replace_field(&mut unleash_context.app_name, &overrides.app_name);
+    if !overrides.properties.is_empty() {
+        unleash_context
+            .properties
+            .get_or_insert_with(HashMap::new)
+            .extend(overrides.properties.clone());
+    }
+}
+
+/// What to do with an environment sent by the client that differs from the token's own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EnvironmentPolicy {
+    /// Evaluate in the token's environment, whatever the client sent
+    Ignore,
+    /// Fail the request with an EnvironmentMismatch error
+    Reject,
+    /// Evaluate in the environment the client sent
+    Allow,
+}
+
+impl EnvironmentPolicy {
+    pub fn apply(&self, token: &EdgeToken, unleash_context: &mut InnerContext) -> EdgeResult<()> {
+        // A token for every environment has no environment of its own to enforce
+        if *self == EnvironmentPolicy::Allow || token.environment == "*" {
+            return Ok(());
+        }
+        match &unleash_context.environment {
+            Some(environment)
+                if *self == EnvironmentPolicy::Reject && *environment != token.environment =>
+            {
+                Err(EdgeError::EnvironmentMismatch)
+            }
+            _ => {
+                unleash_context.environment = Some(token.environment.clone());
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::ApiToken;
+
+    fn token(token: &str, environment: &str) -> EdgeToken {
+        EdgeToken {
+            token: token.to_string(),
+            environment: environment.to_string(),
+            projects: vec!["*".to_string()],
+            token_type: ApiToken::Undecided,
+            dynamic: false,
+        }
+    }
+
+    fn context_in(environment: Option<&str>) -> InnerContext {
+        InnerContext {
+            environment: environment.map(String::from),
+            ..InnerContext::default()
+        }
+    }
+
+    fn rules(json: &str) -> ContextRules {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn ignore_overwrites_the_client_environment() {
+        let token = token("default:production.secret", "production");
+        for environment in [None, Some("production"), Some("development")] {
+            let mut context = context_in(environment);
+            EnvironmentPolicy::Ignore
+                .apply(&token, &mut context)
+                .unwrap();
+            assert_eq!(context.environment.as_deref(), Some("production"));
+        }
+    }
+
+    #[test]
+    fn reject_only_fails_when_the_environments_differ() {
+        let token = token("default:production.secret", "production");
+        let mut different = context_in(Some("development"));
+        assert_eq!(
+            EnvironmentPolicy::Reject.apply(&token, &mut different),
+            Err(EdgeError::EnvironmentMismatch)
+        );
+        let mut same = context_in(Some("production"));
+        assert_eq!(EnvironmentPolicy::Reject.apply(&token, &mut same), Ok(()));
+        assert_eq!(same.environment.as_deref(), Some("production"));
+        let mut missing = context_in(None);
+        assert_eq!(
+            EnvironmentPolicy::Reject.apply(&token, &mut missing),
+            Ok(())
+        );
+        assert_eq!(missing.environment.as_deref(), Some("production"));
+    }
+
+    #[test]
+    fn allow_keeps_the_client_environment() {
+        let token = token("default:production.secret", "production");
+        let mut context = context_in(Some("development"));
+        EnvironmentPolicy::Allow
+            .apply(&token, &mut context)
+            .unwrap();
+        assert_eq!(context.environment.as_deref(), Some("development"));
+    }
+
+    #[test]
+    fn wildcard_token_environment_skips_the_policy() {
+        let token = token("*:*.secret", "*");
+        for policy in [EnvironmentPolicy::Ignore, EnvironmentPolicy::Reject] {
+            let mut context = context_in(Some("development"));
+            assert_eq!(policy.apply(&token, &mut context), Ok(()));
+            assert_eq!(context.environment.as_deref(), Some("development"));
+        }
+    }
+
+    #[test]
+    fn token_defaults_take_precedence_over_wildcard_defaults() {
+        let token = token("default:production.secret", "production");
+        let rules = rules(
+            r#"{
+                "*": {"defaults": {
+                    "appName": "any", "sessionId": "any", "properties": {"tier": "any"}
+                }},
+                "default:production.secret": {"defaults": {
+                    "appName": "mine", "properties": {"tier": "mine"}
+                }}
+            }"#,
+        );
+        let mut context = InnerContext {
+            user_id: Some("client".to_string()),
+            ..InnerContext::default()
+        };
+        rules.apply(&token, &mut context);
+        assert_eq!(context.app_name.as_deref(), Some("mine"));
+        assert_eq!(context.session_id.as_deref(), Some("any"));
+        assert_eq!(context.user_id.as_deref(), Some("client"));
+        assert_eq!(
+            context.properties.unwrap().get("tier").map(String::as_str),
+            Some("mine")
+        );
+    }
+
+    #[test]
+    fn defaults_do_not_replace_client_values() {
+        let token = token("default:production.secret", "production");
+        let rules = rules(r#"{"*": {"defaults": {"appName": "any"}}}"#);
+        let mut context = InnerContext {
+            app_name: Some("client".to_string()),
+            ..InnerContext::default()
+        };
+        rules.apply(&token, &mut context);
+        assert_eq!(context.app_name.as_deref(), Some("client"));
+    }
+
+    #[test]
+    fn token_overrides_are_applied_after_wildcard_overrides() {
+        let token = token("default:production.secret", "production");
+        let rules = rules(
+            r#"{
+                "*": {"overrides": {
+                    "environment": "any", "appName": "any", "properties": {"tier": "any"}
+                }},
+                "default:production.secret": {"overrides": {
+                    "environment": "mine", "properties": {"tier": "mine"}
+                }}
+            }"#,
+        );
+        let mut context = InnerContext {
+            environment: Some("client".to_string()),
+            app_name: Some("client".to_string()),
+            ..InnerContext::default()
+        };
+        rules.apply(&token, &mut context);
+        assert_eq!(context.environment.as_deref(), Some("mine"));
+        assert_eq!(context.app_name.as_deref(), Some("any"));
+        assert_eq!(
+            context.properties.unwrap().get("tier").map(String::as_str),
+            Some("mine")
+        );
+    }
+
+    #[test]
+    fn rules_for_other_tokens_are_not_applied() {
+        let token = token("default:production.secret", "production");
+        let rules = rules(r#"{"other:production.secret": {"overrides": {"appName": "other"}}}"#);
+        let mut context = InnerContext::default();
+        rules.apply(&token, &mut context);
+        assert!(context.app_name.is_none());
+    }
+}
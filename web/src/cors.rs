@@ -0,0 +1,82 @@
+use crate::EdgeConfig;
+use actix_cors::Cors;
+use actix_web::http::header::ETAG;
+use actix_web::http::Uri;
+use actix_web::middleware::Condition;
+
+/// Used by clap to check each origin given with `--cors-origins`. Browsers send origins as
+/// scheme://host[:port] with nothing after, so anything else would never match
+pub fn parse_origin(origin: &str) -> Result<String, String> {
+    if origin == "*" {
+        return Ok(origin.to_string());
+    }
+    let invalid = |reason: &str| {
+        Err(format!(
+            "{} is not a valid CORS origin, {}. Use scheme://host[:port] or *",
+            origin, reason
+        ))
+    };
+    let uri = match origin.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => return invalid("it is not a URL"),
+    };
+    match (uri.scheme(), uri.authority()) {
+        (None, _) | (_, None) => invalid("it needs a scheme and a host"),
+        (_, Some(authority)) if authority.as_str().contains('@') => {
+            invalid("it can't contain credentials")
+        }
+        _ if origin.ends_with('/') => invalid("it can't end with a slash"),
+        _ if uri.path() != "/" || uri.query().is_some() => invalid("it can't have a path"),
+        _ => Ok(origin.to_string()),
+    }
+}
+
+/// CORS for the scopes browsers call directly. Only enabled when at least one origin is
+/// configured, so Edge behaves exactly as before for deployments that don't serve browsers
+pub fn cors_middleware(config: &EdgeConfig) -> Condition<Cors> {
+    let origins = &config.cors_origins;
+    let cors = if origins.iter().any(|origin| origin == "*") {
+        Cors::default().allow_any_origin()
+    } else {
+        origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+    }
+    .allowed_methods(vec!["GET", "POST"])
+    .allowed_headers(config.cors_allowed_headers.iter().map(String::as_str))
+    .expose_headers(vec![ETAG])
+    .max_age(config.cors_max_age);
+    Condition::new(!origins.is_empty(), cors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origins_and_the_wildcard_are_accepted() {
+        for origin in [
+            "*",
+            "https://app.example.com",
+            "http://localhost:3000",
+            "capacitor://localhost",
+        ] {
+            assert_eq!(parse_origin(origin), Ok(origin.to_string()));
+        }
+    }
+
+    #[test]
+    fn anything_a_browser_would_not_send_is_refused() {
+        for origin in [
+            "app.example.com",
+            "https://",
+            "https://app.example.com/",
+            "https://app.example.com/path",
+            "https://app.example.com?query=1",
+            "https://user@app.example.com",
+            "not an origin",
+        ] {
+            assert!(parse_origin(origin).is_err(), "{} was accepted", origin);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate::compression::{self, Encoding};
+use actix_web::http::header::{ContentType, ETag, EntityTag, IfNoneMatch, CONTENT_ENCODING, VARY};
+use actix_web::web::Bytes;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use types::{EdgeError, EdgeResult};
+
+/// Hashes a serialized payload into an opaque ETag value. DefaultHasher::new() uses fixed keys,
+/// so the same payload gets the same tag across requests and restarts of the same build
+pub fn compute_etag(bytes: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    format!("{:x}", hasher.finish())
+}
+
+/// Computes the ETag of a payload from its canonical JSON. Maps such as strategy parameters
+/// serialize in a different order every time they are deserialized, while serde_json::Value
+/// keeps object keys sorted
+pub fn canonical_etag<T: Serialize>(body: &T) -> EdgeResult<String> {
+    let canonical = serde_json::to_value(body)
+        .and_then(|value| serde_json::to_vec(&value))
+        .map_err(|_| EdgeError::CouldNotSerialize)?;
+    Ok(compute_etag(&canonical))
+}
+
+/// Serializes the body and tags it with an ETag computed from its canonical JSON
+pub fn json_response<T: Serialize>(req: &HttpRequest, body: &T) -> EdgeResult<HttpResponse> {
+    let etag = canonical_etag(body)?;
+    if is_fresh(req, &etag) {
+        return Ok(not_modified(req, &etag));
+    }
+    let bytes = serde_json::to_vec(body).map_err(|_| EdgeError::CouldNotSerialize)?;
+    let (encoding, body) = compression::encode(req, bytes, None)?;
+    Ok(respond(&etag, encoding, body))
+}
+
+/// Uses an ETag computed ahead of time, so the body is only serialized if the client's copy is
+/// stale. With a token, compressed copies of the payload are kept and reused until the ETag changes
+pub fn cached_json_response<T: Serialize>(
+    req: &HttpRequest,
+    etag: &str,
+    token: Option<&str>,
+    body: &T,
+) -> EdgeResult<HttpResponse> {
+    if is_fresh(req, etag) {
+        return Ok(not_modified(req, etag));
+    }
+    if let Some((encoding, body)) = token.and_then(|token| compression::cached(req, token, etag)) {
+        return Ok(respond(etag, Some(encoding), body));
+    }
+    let bytes = serde_json::to_vec(body).map_err(|_| EdgeError::CouldNotSerialize)?;
+    let (encoding, body) = compression::encode(req, bytes, token.map(|token| (token, etag)))?;
+    Ok(respond(etag, encoding, body))
+}
+
+/// A strong validator has to differ between content codings, so compressed bodies are tagged
+/// with a weak one. If-None-Match is compared weakly, so either form of the tag matches
+fn entity_tag(etag: &str, encoding: Option<Encoding>) -> EntityTag {
+    match encoding {
+        Some(_) => EntityTag::new_weak(etag.to_string()),
+        None => EntityTag::new_strong(etag.to_string()),
+    }
+}
+
+fn respond(etag: &str, encoding: Option<Encoding>, body: Bytes) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(ETag(entity_tag(etag, encoding)))
+        .insert_header(ContentType::json())
+        .insert_header((VARY, "Accept-Encoding"));
+    if let Some(encoding) = encoding {
+        response.insert_header((CONTENT_ENCODING, encoding.as_str()));
+    }
+    response.body(body)
+}
+
+fn not_modified(req: &HttpRequest, etag: &str) -> HttpResponse {
+    HttpResponse::NotModified()
+        .insert_header(ETag(entity_tag(etag, compression::negotiate(req))))
+        .finish()
+}
+
+fn is_fresh(req: &HttpRequest, etag: &str) -> bool {
+    let etag = EntityTag::new_strong(etag.to_string());
+    match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{ETAG, IF_NONE_MATCH};
+    use actix_web::test::TestRequest;
+    use unleash_types::client_features::ClientFeatures;
+
+    #[test]
+    fn etag_does_not_depend_on_the_order_of_strategy_parameters() {
+        let json = r#"{
+            "version": 2,
+            "features": [{
+                "name": "rollout",
+                "enabled": true,
+                "strategies": [{
+                    "name": "flexibleRollout",
+                    "parameters": {"rollout": "50", "stickiness": "default", "groupId": "rollout"}
+                }]
+            }]
+        }"#;
+        let first: ClientFeatures = serde_json::from_str(json).unwrap();
+        let second: ClientFeatures = serde_json::from_str(json).unwrap();
+        assert_eq!(canonical_etag(&first), canonical_etag(&second));
+    }
+
+    #[test]
+    fn compressed_bodies_get_a_weak_etag() {
+        let compressed = respond("abc", Some(Encoding::Gzip), Bytes::new());
+        assert_eq!(compressed.headers().get(ETAG).unwrap(), "W/\"abc\"");
+        let identity = respond("abc", None, Bytes::new());
+        assert_eq!(identity.headers().get(ETAG).unwrap(), "\"abc\"");
+    }
+
+    #[test]
+    fn weak_and_strong_tags_both_match() {
+        for tag in ["W/\"abc\"", "\"abc\""] {
+            let req = TestRequest::default()
+                .insert_header((IF_NONE_MATCH, tag))
+                .to_http_request();
+            assert!(is_fresh(&req, "abc"));
+            assert!(!is_fresh(&req, "def"));
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use crate::item_cache::EngineCache;
+use crate::proxy::{context_from_body, context_from_query, engine_snapshot};
+use actix_web::web::{self, Data, Json};
+use actix_web::HttpRequest;
+use sdk_core::state::InnerContext;
+use sdk_core::EngineState;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Arc;
+use storage::{CachedData, FullState};
+use types::extractors::token_guards::FrontendToken;
+use types::{EdgeError, EdgeResult, EdgeToken};
+use unleash_types::client_features::Payload;
+use unleash_types::frontend::EvaluatedVariant;
+
+/// Strategies whose outcome depends on the context's stickiness bucket
+const ROLLOUT_STRATEGIES: [&str; 3] = [
+    "flexibleRollout",
+    "gradualRolloutUserId",
+    "gradualRolloutSessionId",
+];
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Explanation {
+    feature_name: String,
+    /// Whether the feature itself is switched on in this environment
+    feature_enabled: bool,
+    /// The final result, as computed by the engine serving regular requests
+    enabled: bool,
+    strategies: Vec<StrategyExplanation>,
+    variant: EvaluatedVariant,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StrategyExplanation {
+    name: String,
+    parameters: Option<HashMap<String, String>>,
+    enabled: bool,
+    constraints: Vec<ConditionExplanation>,
+    segments: Vec<SegmentExplanation>,
+    stickiness_bucket: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SegmentExplanation {
+    id: i32,
+    matched: bool,
+    constraints: Vec<ConditionExplanation>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConditionExplanation {
+    constraint: serde_json::Value,
+    matched: bool,
+}
+
+/// Only registered when explain is enabled, see proxy::configure_proxy
+pub async fn get_explanation(
+    FrontendToken(token): FrontendToken,
+    feature_name: web::Path<String>,
+    all_tokens: Data<Arc<FullState>>,
+    engines: Data<Arc<EngineCache>>,
+    req: HttpRequest,
+) -> EdgeResult<Json<Explanation>> {
+    let unleash_context = context_from_query(&token, &req)?;
+    explain(
+        &token,
+        &feature_name,
+        &all_tokens,
+        &engines,
+        &unleash_context,
+    )
+    .map(Json)
+}
+
+pub async fn post_explanation(
+    FrontendToken(token): FrontendToken,
+    feature_name: web::Path<String>,
+    all_tokens: Data<Arc<FullState>>,
+    engines: Data<Arc<EngineCache>>,
+    context: Json<InnerContext>,
+    req: HttpRequest,
+) -> EdgeResult<Json<Explanation>> {
+    let unleash_context = context_from_body(&token, context.into_inner(), &req)?;
+    explain(
+        &token,
+        &feature_name,
+        &all_tokens,
+        &engines,
+        &unleash_context,
+    )
+    .map(Json)
+}
+
+/// Explains a toggle by replaying each strategy, constraint and segment on its own through a
+/// throwaway engine, so every partial result comes from the same evaluation code as the real one
+fn explain(
+    token: &EdgeToken,
+    feature_name: &str,
+    all_tokens: &FullState,
+    engines: &EngineCache,
+    unleash_context: &InnerContext,
+) -> EdgeResult<Explanation> {
+    let snapshot = engine_snapshot(token, engines).ok_or(EdgeError::FeatureNotFound)?;
+    let engine = &snapshot.engine;
+    let cached = all_tokens
+        .data
+        .get(&token.token)
+        .ok_or(EdgeError::FeatureNotFound)?;
+    let features = &cached.client_features;
+    let feature = features
+        .features
+        .iter()
+        .find(|feature| {
+            feature.name == feature_name && token.has_access_to_project(feature.project.as_deref())
+        })
+        .ok_or(EdgeError::FeatureNotFound)?;
+
+    // Probes only need the probed feature and the segments it refers to. The empty features of
+    // a fresh cache entry are used as the template, being of the type the engine takes
+    let mut probe_template = CachedData::default().client_features;
+    probe_template.version = features.version;
+    probe_template.segments = features.segments.clone();
+    let probe = |strategy| {
+        let mut probe_feature = feature.clone();
+        probe_feature.enabled = true;
+        probe_feature.strategies = Some(vec![strategy]);
+        let mut probe_features = probe_template.clone();
+        probe_features.features = vec![probe_feature];
+        let mut probe_engine = EngineState::new();
+        probe_engine.take_state(probe_features);
+        probe_engine.is_enabled(feature_name.to_string(), unleash_context)
+    };
+
+    let strategies = feature
+        .strategies
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|strategy| {
+            // A plain default strategy is only enabled when all the constraints it is given match
+            let mut base = strategy.clone();
+            base.name = "default".into();
+            base.parameters = None;
+            base.segments = None;
+            base.constraints = None;
+            let all_match = |constraints| {
+                let mut only_constraints = base.clone();
+                only_constraints.constraints = Some(constraints);
+                probe(only_constraints)
+            };
+            let explain_constraint = |constraint| ConditionExplanation {
+                constraint: serde_json::to_value(&constraint).unwrap_or_default(),
+                matched: all_match(vec![constraint]),
+            };
+            let constraints = strategy
+                .constraints
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .map(&explain_constraint)
+                .collect();
+            let segments = strategy
+                .segments
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|segment_id| {
+                    let segment_constraints = features
+                        .segments
+                        .as_ref()
+                        .and_then(|segments| segments.iter().find(|s| s.id == segment_id))
+                        .map(|segment| segment.constraints.clone())
+                        .unwrap_or_default();
+                    SegmentExplanation {
+                        id: segment_id,
+                        matched: all_match(segment_constraints.clone()),
+                        constraints: segment_constraints
+                            .into_iter()
+                            .map(&explain_constraint)
+                            .collect(),
+                    }
+                })
+                .collect();
+            StrategyExplanation {
+                stickiness_bucket: stickiness_bucket(
+                    &strategy.name,
+                    strategy.parameters.as_ref(),
+                    feature_name,
+                    unleash_context,
+                ),
+                name: strategy.name.clone(),
+                parameters: strategy.parameters.clone(),
+                enabled: probe(strategy),
+                constraints,
+                segments,
+            }
+        })
+        .collect();
+
+    let variant = engine.get_variant(feature_name.to_string(), unleash_context);
+    Ok(Explanation {
+        feature_name: feature_name.to_string(),
+        feature_enabled: feature.enabled,
+        enabled: engine.is_enabled(feature_name.to_string(), unleash_context),
+        strategies,
+        variant: EvaluatedVariant {
+            name: variant.name,
+            enabled: variant.enabled,
+            payload: variant.payload.map(|succ| Payload {
+                payload_type: succ.payload_type,
+                value: succ.value,
+            }),
+        },
+    })
+}
+
+/// The 1-100 bucket a rollout strategy puts this context in, computed the same way as the
+/// Unleash SDKs: murmur3 of `groupId:identifier`. None when there is nothing to be sticky on
+fn stickiness_bucket(
+    strategy_name: &str,
+    parameters: Option<&HashMap<String, String>>,
+    feature_name: &str,
+    unleash_context: &InnerContext,
+) -> Option<u32> {
+    if !ROLLOUT_STRATEGIES.contains(&strategy_name) {
+        return None;
+    }
+    let parameter = |name: &str| parameters.and_then(|p| p.get(name)).cloned();
+    let stickiness = match strategy_name {
+        "gradualRolloutUserId" => "userId".to_string(),
+        "gradualRolloutSessionId" => "sessionId".to_string(),
+        _ => parameter("stickiness").unwrap_or_else(|| "default".to_string()),
+    };
+    let identifier = match stickiness.as_str() {
+        "default" => unleash_context
+            .user_id
+            .clone()
+            .or_else(|| unleash_context.session_id.clone()),
+        "userId" => unleash_context.user_id.clone(),
+        "sessionId" => unleash_context.session_id.clone(),
+        property => unleash_context
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.get(property).cloned()),
+    }?;
+    let group_id = parameter("groupId").unwrap_or_else(|| feature_name.to_string());
+    murmur3::murmur3_32(&mut Cursor::new(format!("{}:{}", group_id, identifier)), 0)
+        .ok()
+        .map(|hash| hash % 100 + 1)
+}
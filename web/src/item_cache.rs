@@ -1,71 +1,147 @@
-use crate::EdgeConfig;
+use crate::{etag, EdgeConfig};
 use actix_web::web::Data;
 use chrono::Utc;
+use dashmap::DashMap;
+use sdk_core::EngineState;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 use storage::{CachedData, FullState, Repository, Status, ToggleSink, TokenStore};
+use tokio::sync::broadcast;
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{error, info, warn};
+use types::{ApiToken, ApiTokenType, EdgeResult, EdgeToken};
 use unleash_types::client_features::ClientFeatures;
 
-pub(crate) fn init_token_refresher(
+/// What evaluating a toggle needs to know about it besides its strategies
+#[derive(Debug, Clone)]
+pub struct ToggleInfo {
+    pub name: String,
+    pub project: Option<String>,
+    pub enabled: bool,
+    pub impression_data: bool,
+}
+
+/// An engine together with the toggles it was compiled from. Both are swapped in as one, so a
+/// request that looks up the snapshot once evaluates a single version of the features
+pub struct EngineSnapshot {
+    pub engine: EngineState,
+    pub toggles: Vec<ToggleInfo>,
+}
+
+/// Compiled evaluation engines keyed by token, rebuilt whenever the refresher stores new features
+pub type EngineCache = DashMap<String, Arc<EngineSnapshot>>;
+
+/// Publishes the token whose features changed every time the refresher installs new data
+pub type FeatureUpdates = broadcast::Sender<String>;
+
+/// How long startup waits for the first fetch before serving without it
+const INITIAL_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sets up the cache, registers the preapproved tokens from the config and fetches their
+/// features before returning, so Edge can serve them as soon as it starts listening. An
+/// upstream that doesn't answer in time doesn't hold up startup, the refresh job retries it
+pub(crate) async fn init_token_refresher(
     repository: Arc<impl Repository>,
     client: reqwest::Client,
     config: EdgeConfig,
-) -> (Arc<FullState>, JoinHandle<()>, CancellationToken) {
+) -> EdgeResult<(
+    Arc<FullState>,
+    Arc<EngineCache>,
+    FeatureUpdates,
+    JoinHandle<()>,
+    CancellationToken,
+)> {
     let current_state = Arc::new(repository.init());
+    let engine_cache = Arc::new(EngineCache::new());
+    let (feature_updates, _) = broadcast::channel(128);
+
+    for token in preapproved_tokens(&config)? {
+        info!("Registering preapproved token for {}", token.environment);
+        current_state
+            .data
+            .insert(token.token.clone(), CachedData::default());
+        repository.add_token(token).await?;
+    }
+    let initial_fetch = refresh_features(
+        &current_state,
+        &engine_cache,
+        &feature_updates,
+        &client,
+        &config,
+    );
+    if tokio::time::timeout(INITIAL_FETCH_TIMEOUT, initial_fetch)
+        .await
+        .is_err()
+    {
+        warn!(
+            "Upstream did not answer within {:?}, starting without the features of every token",
+            INITIAL_FETCH_TIMEOUT
+        );
+    }
 
     // Stop signal for token refresh job
     let token_refresh_cancel = CancellationToken::new();
     // Spawn refresh job
-    (
+    Ok((
         Arc::clone(&current_state),
+        Arc::clone(&engine_cache),
+        feature_updates.clone(),
         tokio::spawn(spawn_token_refresh(
             Arc::clone(&current_state),
+            Arc::clone(&engine_cache),
+            feature_updates,
             client.clone(),
             token_refresh_cancel.clone(),
             config.clone(),
         )),
         token_refresh_cancel,
-    )
+    ))
+}
+
+/// The tokens given on the command line are trusted as client tokens without asking upstream.
+/// Tokens for several projects (`[]`) don't name their projects, so they stay undecided and are
+/// validated upstream the first time they are used. A token that doesn't parse is reported by
+/// its position in the list, never by its secret
+fn preapproved_tokens(config: &EdgeConfig) -> EdgeResult<Vec<EdgeToken>> {
+    config
+        .tokens
+        .iter()
+        .enumerate()
+        .map(|(position, raw)| {
+            EdgeToken::from_str(raw)
+                .map(|token| match token.token_type {
+                    ApiToken::Undecided if !token.projects.is_empty() => EdgeToken {
+                        token_type: ApiToken::Decided(ApiTokenType::Client),
+                        ..token
+                    },
+                    _ => token,
+                })
+                .map_err(|e| {
+                    error!(
+                        "Token number {} given with --tokens is invalid: {}",
+                        position + 1,
+                        e
+                    );
+                    e
+                })
+        })
+        .collect()
 }
 
 async fn spawn_token_refresh(
     state: Arc<FullState>,
+    engines: Arc<EngineCache>,
+    updates: FeatureUpdates,
     client: reqwest::Client,
     stop_signal: CancellationToken,
     config: EdgeConfig,
 ) {
     loop {
-        for entry in &state.data {
-            let token = entry.key().clone();
-            info!("Fetching data for {}", token);
-            let updated = fetcher::fetch_client_features(
-                client.clone(),
-                format!("{}/api/client/features", config.unleash_url.clone()),
-                token.clone(),
-            )
-            .await
-            .map(|features| CachedData {
-                status: Status {
-                    ready: true,
-                    last_fetch: Some(Utc::now()),
-                    error: None,
-                },
-                client_features: features.clone(),
-            });
-            if let Ok(updated_data) = updated {
-                info!(
-                    "Data was updated. Has {} features",
-                    updated_data.client_features.features.len()
-                );
-                state.data.alter(token.as_str(), |_key, _val| updated_data);
-            }
-        }
         tokio::select! {
             _ = tokio::time::sleep(Duration::from_secs(config.client_feature_refresh_interval)) => {
-                continue;
+                refresh_features(&state, &engines, &updates, &client, &config).await;
             }
             _ = stop_signal.cancelled() => {
                 info!("gracefully shutting down toggle refresh job");
@@ -74,3 +150,78 @@ async fn spawn_token_refresh(
         };
     }
 }
+
+/// Fetches the features of every known token once
+async fn refresh_features(
+    state: &FullState,
+    engines: &EngineCache,
+    updates: &FeatureUpdates,
+    client: &reqwest::Client,
+    config: &EdgeConfig,
+) {
+    // Collect the keys up front so no shard lock is held across the fetch or the update below
+    let tokens: Vec<String> = state.data.iter().map(|entry| entry.key().clone()).collect();
+    for token in tokens {
+        refresh_token(state, engines, updates, client, config, token).await;
+    }
+}
+
+/// Fetches the features of a single token, rebuilding its engine and announcing the update when
+/// they changed. A failed fetch keeps the data the token already had
+pub(crate) async fn refresh_token(
+    state: &FullState,
+    engines: &EngineCache,
+    updates: &FeatureUpdates,
+    client: &reqwest::Client,
+    config: &EdgeConfig,
+    token: String,
+) {
+    info!("Fetching data for {}", token);
+    let updated = fetcher::fetch_client_features(
+        client.clone(),
+        format!("{}/api/client/features", config.unleash_url.clone()),
+        token.clone(),
+    )
+    .await
+    .map(|features| CachedData {
+        status: Status {
+            ready: true,
+            last_fetch: Some(Utc::now()),
+            error: None,
+        },
+        etag: etag::canonical_etag(&features).ok(),
+        client_features: features,
+    });
+    if let Ok(updated_data) = updated {
+        info!(
+            "Data was updated. Has {} features",
+            updated_data.client_features.features.len()
+        );
+        let changed = state
+            .data
+            .get(&token)
+            .map(|current| current.etag != updated_data.etag)
+            .unwrap_or(true);
+        if changed {
+            let toggles = updated_data
+                .client_features
+                .features
+                .iter()
+                .map(|feature| ToggleInfo {
+                    name: feature.name.clone(),
+                    project: feature.project.clone(),
+                    enabled: feature.enabled,
+                    impression_data: feature.impression_data.unwrap_or(false),
+                })
+                .collect();
+            let mut engine = EngineState::new();
+            engine.take_state(updated_data.client_features.clone());
+            engines.insert(token.clone(), Arc::new(EngineSnapshot { engine, toggles }));
+        }
+        state.data.alter(token.as_str(), |_key, _val| updated_data);
+        if changed {
+            // Only fails when nobody is subscribed, which is fine
+            let _ = updates.send(token);
+        }
+    }
+}
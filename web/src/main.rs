@@ -2,6 +2,7 @@ use actix_web::web::{Data, Json};
 use actix_web::{middleware, web, App, HttpServer};
 use actix_web_opentelemetry::{PrometheusMetricsHandler, RequestMetricsBuilder, RequestTracing};
 use clap::Parser;
+use ipnet::IpNet;
 use opentelemetry::global;
 use opentelemetry::sdk::export::metrics::aggregation;
 use opentelemetry::sdk::metrics::{controllers, processors, selectors};
@@ -12,11 +13,21 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{EnvFilter, Registry};
 
 mod backstage;
+mod compression;
+mod context_rules;
+mod cors;
 use types::EdgeError;
 
+mod etag;
+mod explain;
 mod item_cache;
+mod ofrep;
 mod proxy;
+mod remote_address;
+mod stream;
 mod telemetry;
+mod token_validator;
+mod websocket;
 
 pub type EdgeJsonResult<T> = Result<Json<T>, EdgeError>;
 
@@ -26,10 +37,20 @@ pub struct EdgeConfig {
     #[clap(short, long, env)]
     pub unleash_url: String,
 
-    /// A list of client tokens (Optional) that is preapproved
-    #[clap(short, long, env)]
+    /// A list of client tokens (Optional) that is preapproved. Their features are fetched
+    /// before Edge starts serving, unless upstream takes too long to answer
+    #[clap(short, long, env, value_delimiter = ',')]
     pub tokens: Vec<String>,
 
+    /// Only serve the preapproved and registered tokens. Without it, unknown tokens that upstream
+    /// validates are learned and refreshed like any other token
+    #[clap(long, env)]
+    pub strict: bool,
+
+    /// Most tokens to learn from incoming requests when not in strict mode
+    #[clap(long, env, default_value_t = 100)]
+    pub max_dynamic_tokens: usize,
+
     /// Which port should Edge bind to
     #[clap(short, long, env)]
     pub port: Option<u16>,
@@ -41,6 +62,50 @@ pub struct EdgeConfig {
     /// How often to refresh features for a client key (in seconds)
     #[clap(short, long, env, default_value_t = 15)]
     pub client_feature_refresh_interval: u64,
+
+    /// Proxies (in CIDR notation) allowed to report the client address through the
+    /// X-Forwarded-For or Forwarded headers. When empty the connecting peer is used as is
+    #[clap(long, env, value_delimiter = ',')]
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Expose /api/frontend/features/{featureName}/explain, which details how a toggle was
+    /// evaluated for a context. Meant for debugging, so it is off by default
+    #[clap(long, env)]
+    pub enable_explain: bool,
+
+    /// Smallest response body (in bytes) to compress with gzip or brotli when the client
+    /// accepts it
+    #[clap(long, env, default_value_t = 1024)]
+    pub compression_min_size: usize,
+
+    /// Origins allowed to call the /api and /ofrep endpoints from a browser. Use * to allow any
+    /// origin. CORS is disabled when no origins are given
+    #[clap(long, env, value_delimiter = ',', value_parser = cors::parse_origin)]
+    pub cors_origins: Vec<String>,
+
+    /// Request headers browsers may send to the /api and /ofrep endpoints
+    #[clap(
+        long,
+        env,
+        value_delimiter = ',',
+        default_value = "Authorization,Content-Type,If-None-Match"
+    )]
+    pub cors_allowed_headers: Vec<String>,
+
+    /// How long (in seconds) browsers may cache the result of a preflight request
+    #[clap(long, env, default_value_t = 3600)]
+    pub cors_max_age: usize,
+
+    /// JSON file of per-token context rules: defaults fill in context fields the client left
+    /// out, overrides replace whatever the client sent. Use * as the token to match any token
+    #[clap(long, env, value_parser = context_rules::ContextRules::from_file)]
+    pub context_rules: Option<context_rules::ContextRules>,
+
+    /// How to treat an environment in the client's context that differs from the token's
+    /// environment. ignore evaluates in the token's environment, reject fails the request and
+    /// allow evaluates in the environment the client sent
+    #[clap(long, env, value_enum, default_value_t = context_rules::EnvironmentPolicy::Ignore)]
+    pub environment_policy: context_rules::EnvironmentPolicy,
 }
 
 #[tokio::main]
@@ -89,8 +154,20 @@ async fn main() -> Result<(), EdgeError> {
     let http_client = ClientBuilder::new()
         .build()
         .map_err(|_| EdgeError::NoHttpClient)?;
-    let (toggle_cache, toggle_refresher, toggle_refresh_cancel) =
-        item_cache::init_token_refresher(toggle_source.clone(), http_client.clone(), args.clone());
+    let (toggle_cache, engine_cache, feature_updates, toggle_refresher, toggle_refresh_cancel) =
+        item_cache::init_token_refresher(toggle_source.clone(), http_client.clone(), args.clone())
+            .await?;
+    let compression_cache = Arc::new(compression::CompressionCache::new());
+    let tag_filter_cache = Arc::new(proxy::TagFilterCache::new());
+    let token_validator = Arc::new(token_validator::TokenValidator::new(
+        &args,
+        http_client.clone(),
+        toggle_source.clone(),
+        toggle_cache.clone(),
+        engine_cache.clone(),
+        feature_updates.clone(),
+    ));
+    let edge_config = args.clone();
     let server = HttpServer::new(move || {
         App::new()
             .wrap(RequestTracing::new())
@@ -98,12 +175,30 @@ async fn main() -> Result<(), EdgeError> {
             .wrap(middleware::Logger::default().exclude("/internal-backstage"))
             .app_data(Data::new(toggle_source.clone()))
             .app_data(Data::new(toggle_cache.clone()))
+            .app_data(Data::new(engine_cache.clone()))
+            .app_data(Data::new(compression_cache.clone()))
+            .app_data(Data::new(tag_filter_cache.clone()))
+            .app_data(Data::new(feature_updates.clone()))
+            .app_data(Data::new(http_client.clone()))
+            .app_data(Data::new(token_validator.clone()))
+            .app_data(Data::new(edge_config.clone()))
             .service(
                 web::resource("/internal-backstage/metrics")
                     .route(web::get().to(metrics_handler.clone())),
             )
             .service(web::scope("/internal-backstage").configure(backstage::configure_backstage))
-            .service(web::scope("/api").configure(proxy::configure_proxy))
+            .service(
+                web::scope("/api")
+                    .wrap(token_validator::ValidateTokens)
+                    .wrap(cors::cors_middleware(&edge_config))
+                    .configure(|cfg| proxy::configure_proxy(cfg, &edge_config)),
+            )
+            .service(
+                web::scope("/ofrep/v1")
+                    .wrap(token_validator::ValidateTokens)
+                    .wrap(cors::cors_middleware(&edge_config))
+                    .configure(ofrep::configure_ofrep),
+            )
     })
     .bind((
         args.ip.unwrap_or("0.0.0.0".into()),
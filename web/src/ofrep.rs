@@ -0,0 +1,355 @@
+use crate::etag;
+use crate::item_cache::EngineCache;
+use crate::proxy::{context_from_body, engine_snapshot};
+use actix_web::web::{self, Bytes, Data};
+use actix_web::{HttpRequest, HttpResponse};
+use sdk_core::state::InnerContext;
+use sdk_core::EngineState;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use types::extractors::token_guards::PublicToken;
+use types::{EdgeResult, EdgeToken};
+
+/// Body of both OFREP evaluation requests
+#[derive(Debug, Default, Deserialize)]
+pub struct OfrepRequest {
+    #[serde(default)]
+    context: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OfrepEvaluation {
+    key: String,
+    value: Value,
+    reason: &'static str,
+    variant: String,
+    metadata: HashMap<String, Value>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OfrepError {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    error_code: &'static str,
+    error_details: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OfrepBulkEvaluation {
+    flags: Vec<OfrepEvaluation>,
+}
+
+async fn evaluate_flag(
+    PublicToken(token): PublicToken,
+    key: web::Path<String>,
+    engines: Data<Arc<EngineCache>>,
+    body: Bytes,
+    req: HttpRequest,
+) -> EdgeResult<HttpResponse> {
+    let key = key.into_inner();
+    let unleash_context = match parse_context(&token, &body, &req) {
+        Ok(context) => context,
+        Err((error_code, details)) => {
+            return Ok(HttpResponse::BadRequest().json(OfrepError {
+                key: Some(key),
+                error_code,
+                error_details: details,
+            }))
+        }
+    };
+    let snapshot = engine_snapshot(&token, &engines);
+    let feature_enabled = snapshot.as_ref().and_then(|snapshot| {
+        snapshot
+            .toggles
+            .iter()
+            .find(|toggle| {
+                toggle.name == key && token.has_access_to_project(toggle.project.as_deref())
+            })
+            .map(|toggle| toggle.enabled)
+    });
+    match (snapshot, feature_enabled) {
+        (Some(snapshot), Some(feature_enabled)) => Ok(HttpResponse::Ok().json(evaluate(
+            &snapshot.engine,
+            &key,
+            feature_enabled,
+            &unleash_context,
+        ))),
+        _ => Ok(HttpResponse::NotFound().json(OfrepError {
+            error_details: format!("Flag {} was not found", key),
+            key: Some(key),
+            error_code: "FLAG_NOT_FOUND",
+        })),
+    }
+}
+
+async fn evaluate_flags(
+    PublicToken(token): PublicToken,
+    engines: Data<Arc<EngineCache>>,
+    body: Bytes,
+    req: HttpRequest,
+) -> EdgeResult<HttpResponse> {
+    let unleash_context = match parse_context(&token, &body, &req) {
+        Ok(context) => context,
+        Err((error_code, details)) => {
+            return Ok(HttpResponse::BadRequest().json(OfrepError {
+                key: None,
+                error_code,
+                error_details: details,
+            }))
+        }
+    };
+    let flags = engine_snapshot(&token, &engines)
+        .map(|snapshot| {
+            snapshot
+                .toggles
+                .iter()
+                .filter(|toggle| token.has_access_to_project(toggle.project.as_deref()))
+                .map(|toggle| {
+                    evaluate(
+                        &snapshot.engine,
+                        &toggle.name,
+                        toggle.enabled,
+                        &unleash_context,
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    etag::json_response(&req, &OfrepBulkEvaluation { flags })
+}
+
+/// Maps the OpenFeature evaluation context onto an Unleash context. The targeting key becomes
+/// the userId, the Unleash context fields are read by name and everything else is a property.
+/// Errors carry the OFREP error code along with the details
+fn parse_context(
+    token: &EdgeToken,
+    body: &Bytes,
+    req: &HttpRequest,
+) -> Result<InnerContext, (&'static str, String)> {
+    let request = if body.is_empty() {
+        OfrepRequest::default()
+    } else {
+        serde_json::from_slice::<OfrepRequest>(body).map_err(|e| ("PARSE_ERROR", e.to_string()))?
+    };
+    let mut unleash_context = InnerContext::default();
+    let mut properties = HashMap::new();
+    for (name, value) in request.context {
+        let value = match value {
+            Value::String(value) => value,
+            Value::Null => continue,
+            other => other.to_string(),
+        };
+        match name.as_str() {
+            "targetingKey" | "userId" => unleash_context.user_id = Some(value),
+            "sessionId" => unleash_context.session_id = Some(value),
+            "environment" => unleash_context.environment = Some(value),
+            "appName" => unleash_context.app_name = Some(value),
+            "currentTime" => unleash_context.current_time = Some(value),
+            "remoteAddress" => {}
+            _ => {
+                properties.insert(name, value);
+            }
+        }
+    }
+    if !properties.is_empty() {
+        unleash_context.properties = Some(properties);
+    }
+    context_from_body(token, unleash_context, req)
+        .map_err(|e| ("INVALID_CONTEXT", format!("{:?}", e)))
+}
+
+/// A flag with an enabled variant resolves to the variant, with its payload as the value or
+/// true when it has none. Anything else resolves to whether the flag is enabled. DISABLED is
+/// only reported for flags switched off in Unleash, an enabled flag whose strategies don't match
+/// the context resolves to its DEFAULT
+fn evaluate(
+    engine: &EngineState,
+    key: &str,
+    feature_enabled: bool,
+    unleash_context: &InnerContext,
+) -> OfrepEvaluation {
+    let enabled = engine.is_enabled(key.to_string(), unleash_context);
+    let variant = engine.get_variant(key.to_string(), unleash_context);
+    let metadata = HashMap::from([("enabled".to_string(), Value::Bool(enabled))]);
+    if enabled && variant.enabled {
+        return OfrepEvaluation {
+            key: key.to_string(),
+            value: match variant.payload {
+                Some(payload) => match payload.payload_type.as_str() {
+                    "json" | "number" => {
+                        serde_json::from_str(&payload.value).unwrap_or(Value::String(payload.value))
+                    }
+                    _ => Value::String(payload.value),
+                },
+                None => Value::Bool(true),
+            },
+            reason: "SPLIT",
+            variant: variant.name,
+            metadata,
+        };
+    }
+    OfrepEvaluation {
+        key: key.to_string(),
+        value: Value::Bool(enabled),
+        reason: if enabled {
+            "TARGETING_MATCH"
+        } else if feature_enabled {
+            "DEFAULT"
+        } else {
+            "DISABLED"
+        },
+        variant: if enabled { "enabled" } else { "disabled" }.to_string(),
+        metadata,
+    }
+}
+
+pub fn configure_ofrep(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/evaluate/flags").route(web::post().to(evaluate_flags)))
+        .service(web::resource("/evaluate/flags/{key}").route(web::post().to(evaluate_flag)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+    use types::ApiToken;
+
+    fn token() -> EdgeToken {
+        EdgeToken {
+            token: "*:development.secret".to_string(),
+            environment: "development".to_string(),
+            projects: vec!["*".to_string()],
+            token_type: ApiToken::Undecided,
+            dynamic: false,
+        }
+    }
+
+    fn engine_with(features: Value) -> EngineState {
+        let mut engine = EngineState::new();
+        engine.take_state(
+            serde_json::from_value(json!({"version": 2, "features": features})).unwrap(),
+        );
+        engine
+    }
+
+    fn context_for(user_id: &str) -> InnerContext {
+        InnerContext {
+            user_id: Some(user_id.to_string()),
+            ..InnerContext::default()
+        }
+    }
+
+    fn parse(body: Value) -> InnerContext {
+        let body = Bytes::from(serde_json::to_vec(&body).unwrap());
+        parse_context(&token(), &body, &TestRequest::default().to_http_request()).unwrap()
+    }
+
+    #[test]
+    fn disabled_flags_report_disabled() {
+        let engine = engine_with(json!([{
+            "name": "off",
+            "enabled": false,
+            "strategies": [{"name": "default"}]
+        }]));
+        let evaluation = evaluate(&engine, "off", false, &context_for("7"));
+        assert_eq!(evaluation.reason, "DISABLED");
+        assert_eq!(evaluation.variant, "disabled");
+        assert_eq!(evaluation.value, Value::Bool(false));
+    }
+
+    #[test]
+    fn enabled_flags_that_do_not_match_report_default() {
+        let engine = engine_with(json!([{
+            "name": "targeted",
+            "enabled": true,
+            "strategies": [{"name": "userWithId", "parameters": {"userIds": "1"}}]
+        }]));
+        let evaluation = evaluate(&engine, "targeted", true, &context_for("7"));
+        assert_eq!(evaluation.reason, "DEFAULT");
+        assert_eq!(evaluation.variant, "disabled");
+        assert_eq!(evaluation.value, Value::Bool(false));
+    }
+
+    #[test]
+    fn matching_flags_without_variants_report_targeting_match() {
+        let engine = engine_with(json!([{
+            "name": "targeted",
+            "enabled": true,
+            "strategies": [{"name": "userWithId", "parameters": {"userIds": "7"}}]
+        }]));
+        let evaluation = evaluate(&engine, "targeted", true, &context_for("7"));
+        assert_eq!(evaluation.reason, "TARGETING_MATCH");
+        assert_eq!(evaluation.variant, "enabled");
+        assert_eq!(evaluation.value, Value::Bool(true));
+    }
+
+    #[test]
+    fn enabled_variants_report_split_with_their_name() {
+        let engine = engine_with(json!([
+            {
+                "name": "with-payload",
+                "enabled": true,
+                "strategies": [{"name": "default"}],
+                "variants": [{
+                    "name": "blue",
+                    "weight": 1000,
+                    "stickiness": "default",
+                    "payload": {"type": "json", "value": "{\"color\": \"blue\"}"}
+                }]
+            },
+            {
+                "name": "without-payload",
+                "enabled": true,
+                "strategies": [{"name": "default"}],
+                "variants": [{"name": "green", "weight": 1000, "stickiness": "default"}]
+            }
+        ]));
+        let with_payload = evaluate(&engine, "with-payload", true, &context_for("7"));
+        assert_eq!(with_payload.reason, "SPLIT");
+        assert_eq!(with_payload.variant, "blue");
+        assert_eq!(with_payload.value, json!({"color": "blue"}));
+        let without_payload = evaluate(&engine, "without-payload", true, &context_for("7"));
+        assert_eq!(without_payload.reason, "SPLIT");
+        assert_eq!(without_payload.variant, "green");
+        assert_eq!(without_payload.value, Value::Bool(true));
+    }
+
+    #[test]
+    fn targeting_key_becomes_the_user_id() {
+        let context = parse(json!({"context": {"targetingKey": "7", "sessionId": "s1"}}));
+        assert_eq!(context.user_id.as_deref(), Some("7"));
+        assert_eq!(context.session_id.as_deref(), Some("s1"));
+    }
+
+    #[test]
+    fn other_values_become_properties() {
+        let context = parse(json!({"context": {
+            "companyId": "bricks",
+            "seats": 12,
+            "beta": true,
+            "missing": null
+        }}));
+        let properties = context.properties.unwrap();
+        assert_eq!(
+            properties.get("companyId").map(String::as_str),
+            Some("bricks")
+        );
+        assert_eq!(properties.get("seats").map(String::as_str), Some("12"));
+        assert_eq!(properties.get("beta").map(String::as_str), Some("true"));
+        assert!(!properties.contains_key("missing"));
+    }
+
+    #[test]
+    fn an_empty_body_is_an_empty_context() {
+        let body = Bytes::new();
+        let context =
+            parse_context(&token(), &body, &TestRequest::default().to_http_request()).unwrap();
+        assert!(context.user_id.is_none());
+        assert!(context.properties.is_none());
+    }
+}
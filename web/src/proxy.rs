@@ -1,84 +1,648 @@
-use crate::EdgeJsonResult;
+use crate::item_cache::{EngineCache, EngineSnapshot, ToggleInfo};
+use crate::EdgeConfig;
+use crate::{etag, explain, remote_address, stream, websocket};
 use actix_web::web::{Data, Json};
-use actix_web::{web, HttpRequest};
+use actix_web::{web, HttpRequest, HttpResponse};
+use dashmap::DashMap;
 use sdk_core::state::InnerContext;
 use sdk_core::EngineState;
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use storage::memory::InMemoryRepository;
-use storage::{FullState, Repository, ToggleSource};
-use types::{EdgeError, EdgeToken};
+use storage::FullState;
+use types::extractors::token_guards::{ClientToken, FrontendToken};
+use types::{EdgeError, EdgeResult, EdgeToken};
 use unleash_types::client_features::Payload;
 use unleash_types::frontend::{EvaluatedToggle, EvaluatedVariant, FrontendResult};
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, Default)]
 pub struct QueryData {
     user_id: Option<String>,
     session_id: Option<String>,
     environment: Option<String>,
     app_name: Option<String>,
+    current_time: Option<String>,
     properties: Option<HashMap<String, String>>,
+    toggles: Vec<String>,
 }
 
-async fn evaluate_toggles(
-    token: EdgeToken,
-    all_tokens: Data<Arc<FullState>>,
-    req: HttpRequest,
-) -> EdgeJsonResult<FrontendResult> {
-    let unleash_context = web::Query::<QueryData>::from_query(req.query_string())
-        .map_err(|_| EdgeError::CouldNotParseQuery)
-        .map(|q| {
-            let query = q.into_inner();
-            InnerContext {
-                user_id: query.user_id,
-                session_id: query.session_id,
-                environment: query.environment,
-                app_name: query.app_name,
-                current_time: Some(chrono::Utc::now().to_rfc3339()),
-                remote_address: Some(req.connection_info().host().to_string()),
-                properties: None,
+impl QueryData {
+    /// Parses the query string the way the Unleash proxy does: known context fields are read
+    /// directly, `properties[name]=value` and any other parameter end up in `properties`.
+    /// `remoteAddress` is dropped, the address is always resolved from the connection
+    pub fn from_query_string(query: &str) -> EdgeResult<QueryData> {
+        let pairs = web::Query::<Vec<(String, String)>>::from_query(query)
+            .map_err(|_| EdgeError::CouldNotParseQuery)?
+            .into_inner();
+        let mut query_data = QueryData::default();
+        let mut properties = HashMap::new();
+        for (key, value) in pairs {
+            match key.as_str() {
+                "userId" => query_data.user_id = Some(value),
+                "sessionId" => query_data.session_id = Some(value),
+                "environment" => query_data.environment = Some(value),
+                "appName" => query_data.app_name = Some(value),
+                "currentTime" => query_data.current_time = Some(value),
+                "remoteAddress" => {}
+                "toggles" => query_data.toggles.push(value),
+                _ => {
+                    let property_name = key
+                        .strip_prefix("properties[")
+                        .and_then(|name| name.strip_suffix(']'))
+                        .unwrap_or(&key);
+                    properties.insert(property_name.to_string(), value);
+                }
+            }
+        }
+        if !properties.is_empty() {
+            query_data.properties = Some(properties);
+        }
+        Ok(query_data)
+    }
+}
+
+/// Filters supported by the Unleash client features API
+#[derive(Debug, Default)]
+pub struct FeatureFilters {
+    name_prefix: Option<String>,
+    projects: Vec<String>,
+    tags: Vec<String>,
+}
+
+/// Tag-filtered features fetched from upstream, along with the ETag the token's cached
+/// features had at the time. They are served until the refresher installs new features
+#[derive(Debug)]
+pub struct TagFilteredFeatures {
+    source_etag: Option<String>,
+    etag: String,
+    features: Arc<serde_json::Value>,
+}
+
+/// Tag-filtered features keyed by token and query string
+pub type TagFilterCache = DashMap<(String, String), TagFilteredFeatures>;
+
+impl FeatureFilters {
+    pub fn from_query_string(query: &str) -> EdgeResult<FeatureFilters> {
+        let pairs = web::Query::<Vec<(String, String)>>::from_query(query)
+            .map_err(|_| EdgeError::CouldNotParseQuery)?
+            .into_inner();
+        let mut filters = FeatureFilters::default();
+        for (key, value) in pairs {
+            match key.as_str() {
+                "namePrefix" => filters.name_prefix = Some(value),
+                "project" => filters.projects.push(value),
+                "tag" => filters.tags.push(value),
+                _ => {}
             }
+        }
+        Ok(filters)
+    }
+}
+
+/// POST body of the frontend endpoints: an Unleash context, optionally with the names of the
+/// toggles to evaluate
+#[derive(Debug, Deserialize)]
+pub struct EvaluationRequest {
+    #[serde(flatten)]
+    context: InnerContext,
+    toggles: Option<Vec<String>>,
+}
+
+/// Many contexts evaluated against the same snapshot of the token's features
+#[derive(Debug, Deserialize)]
+pub struct BulkEvaluationRequest {
+    contexts: Vec<InnerContext>,
+    toggles: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkEvaluationResult {
+    results: Vec<FrontendResult>,
+}
+
+/// Bulk requests carry hundreds of contexts, well beyond actix's default JSON payload limit
+const BULK_EVALUATION_PAYLOAD_LIMIT: usize = 2 * 1024 * 1024;
+
+pub(crate) fn context_from_query(token: &EdgeToken, req: &HttpRequest) -> EdgeResult<InnerContext> {
+    let query = QueryData::from_query_string(req.query_string())?;
+    let mut unleash_context = InnerContext {
+        user_id: query.user_id,
+        session_id: query.session_id,
+        environment: query.environment,
+        app_name: query.app_name,
+        current_time: query
+            .current_time
+            .or_else(|| Some(chrono::Utc::now().to_rfc3339())),
+        remote_address: client_address(req),
+        properties: query.properties,
+    };
+    apply_context_rules(token, &mut unleash_context, req)?;
+    Ok(unleash_context)
+}
+
+/// Names passed as repeated `toggles` query parameters, None when all toggles are wanted
+pub(crate) fn toggles_from_query(req: &HttpRequest) -> Option<HashSet<String>> {
+    QueryData::from_query_string(req.query_string())
+        .ok()
+        .filter(|query| !query.toggles.is_empty())
+        .map(|query| query.toggles.into_iter().collect())
+}
+
+/// Whether the client fixed the evaluation time with a `currentTime` query parameter, as
+/// context_from_query fills in the current time otherwise
+pub(crate) fn has_current_time(req: &HttpRequest) -> bool {
+    QueryData::from_query_string(req.query_string())
+        .map(|query| query.current_time.is_some())
+        .unwrap_or(false)
+}
+
+pub(crate) fn context_from_body(
+    token: &EdgeToken,
+    mut unleash_context: InnerContext,
+    req: &HttpRequest,
+) -> EdgeResult<InnerContext> {
+    if unleash_context.current_time.is_none() {
+        unleash_context.current_time = Some(chrono::Utc::now().to_rfc3339());
+    }
+    unleash_context.remote_address = client_address(req);
+    apply_context_rules(token, &mut unleash_context, req)?;
+    Ok(unleash_context)
+}
+
+/// The environment policy only looks at what the client sent, so it runs before the configured
+/// context rules, which are free to set any environment
+fn apply_context_rules(
+    token: &EdgeToken,
+    unleash_context: &mut InnerContext,
+    req: &HttpRequest,
+) -> EdgeResult<()> {
+    if let Some(config) = req.app_data::<Data<EdgeConfig>>() {
+        config.environment_policy.apply(token, unleash_context)?;
+        if let Some(rules) = config.context_rules.as_ref() {
+            rules.apply(token, unleash_context);
+        }
+    }
+    Ok(())
+}
+
+fn client_address(req: &HttpRequest) -> Option<String> {
+    let trusted_proxies = req
+        .app_data::<Data<EdgeConfig>>()
+        .map(|config| config.trusted_proxies.clone())
+        .unwrap_or_default();
+    remote_address::resolve(req, &trusted_proxies)
+}
+
+async fn get_evaluated_toggles(
+    FrontendToken(token): FrontendToken,
+    engines: Data<Arc<EngineCache>>,
+    req: HttpRequest,
+) -> EdgeResult<HttpResponse> {
+    let unleash_context = context_from_query(&token, &req)?;
+    let requested = toggles_from_query(&req);
+    let result = evaluate_toggles(&token, &engines, &unleash_context, requested.as_ref());
+    etag::json_response(&req, &result)
+}
+
+async fn post_evaluated_toggles(
+    FrontendToken(token): FrontendToken,
+    engines: Data<Arc<EngineCache>>,
+    request: Json<EvaluationRequest>,
+    req: HttpRequest,
+) -> EdgeResult<HttpResponse> {
+    let request = request.into_inner();
+    let unleash_context = context_from_body(&token, request.context, &req)?;
+    let requested: Option<HashSet<String>> =
+        request.toggles.map(|toggles| toggles.into_iter().collect());
+    let result = evaluate_toggles(&token, &engines, &unleash_context, requested.as_ref());
+    etag::json_response(&req, &result)
+}
+
+async fn get_enabled_toggles(
+    FrontendToken(token): FrontendToken,
+    engines: Data<Arc<EngineCache>>,
+    req: HttpRequest,
+) -> EdgeResult<HttpResponse> {
+    let unleash_context = context_from_query(&token, &req)?;
+    let requested = toggles_from_query(&req);
+    let result = enabled_only(evaluate_toggles(
+        &token,
+        &engines,
+        &unleash_context,
+        requested.as_ref(),
+    ));
+    etag::json_response(&req, &result)
+}
+
+async fn post_enabled_toggles(
+    FrontendToken(token): FrontendToken,
+    engines: Data<Arc<EngineCache>>,
+    request: Json<EvaluationRequest>,
+    req: HttpRequest,
+) -> EdgeResult<HttpResponse> {
+    let request = request.into_inner();
+    let unleash_context = context_from_body(&token, request.context, &req)?;
+    let requested: Option<HashSet<String>> =
+        request.toggles.map(|toggles| toggles.into_iter().collect());
+    let result = enabled_only(evaluate_toggles(
+        &token,
+        &engines,
+        &unleash_context,
+        requested.as_ref(),
+    ));
+    etag::json_response(&req, &result)
+}
+
+async fn get_evaluated_toggle(
+    FrontendToken(token): FrontendToken,
+    feature_name: web::Path<String>,
+    engines: Data<Arc<EngineCache>>,
+    req: HttpRequest,
+) -> EdgeResult<HttpResponse> {
+    let unleash_context = context_from_query(&token, &req)?;
+    evaluate_single_toggle(&token, &feature_name, &engines, &unleash_context)
+        .and_then(|toggle| etag::json_response(&req, &toggle))
+}
+
+async fn post_evaluated_toggle(
+    FrontendToken(token): FrontendToken,
+    feature_name: web::Path<String>,
+    engines: Data<Arc<EngineCache>>,
+    context: Json<InnerContext>,
+    req: HttpRequest,
+) -> EdgeResult<HttpResponse> {
+    let unleash_context = context_from_body(&token, context.into_inner(), &req)?;
+    evaluate_single_toggle(&token, &feature_name, &engines, &unleash_context)
+        .and_then(|toggle| etag::json_response(&req, &toggle))
+}
+
+async fn post_bulk_evaluation(
+    FrontendToken(token): FrontendToken,
+    engines: Data<Arc<EngineCache>>,
+    request: Json<BulkEvaluationRequest>,
+    req: HttpRequest,
+) -> EdgeResult<HttpResponse> {
+    let request = request.into_inner();
+    let requested: Option<HashSet<String>> =
+        request.toggles.map(|toggles| toggles.into_iter().collect());
+    // Take the snapshot once, so every context sees the same features
+    let snapshot = engine_snapshot(&token, &engines);
+    let results = request
+        .contexts
+        .into_iter()
+        .map(|context| {
+            let unleash_context = context_from_body(&token, context, &req)?;
+            Ok(FrontendResult {
+                toggles: snapshot
+                    .as_ref()
+                    .map(|snapshot| {
+                        visible_toggles(&token, snapshot, requested.as_ref())
+                            .map(|toggle| {
+                                evaluate_toggle(
+                                    &snapshot.engine,
+                                    &toggle.name,
+                                    toggle.impression_data,
+                                    &unleash_context,
+                                )
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            })
         })
-        .unwrap_or(InnerContext::default());
-    let res = all_tokens
-        .get_ref()
+        .collect::<EdgeResult<Vec<FrontendResult>>>()?;
+    etag::json_response(&req, &BulkEvaluationResult { results })
+}
+
+async fn get_client_features(
+    ClientToken(token): ClientToken,
+    all_tokens: Data<Arc<FullState>>,
+    tag_filter_cache: Data<Arc<TagFilterCache>>,
+    client: Data<reqwest::Client>,
+    config: Data<EdgeConfig>,
+    req: HttpRequest,
+) -> EdgeResult<HttpResponse> {
+    let filters = FeatureFilters::from_query_string(req.query_string())?;
+    if !filters.tags.is_empty() {
+        let (etag, features) = tag_filtered_features(
+            &token,
+            &all_tokens,
+            &tag_filter_cache,
+            &client,
+            &config,
+            &req,
+        )
+        .await?;
+        return etag::cached_json_response(&req, &etag, None, features.as_ref());
+    }
+    let (mut client_features, cached_etag) = all_tokens
         .data
         .get(&token.token)
-        .map(|c| {
-            let mut state = EngineState::new();
-            state.take_state(c.client_features.clone());
-            let evaluated_toggles: Vec<EvaluatedToggle> = c
-                .client_features
-                .features
-                .clone()
-                .into_iter()
-                .map(|toggle| {
-                    let variant = state.get_variant(toggle.name.clone(), &unleash_context);
-                    EvaluatedToggle {
-                        name: toggle.name.clone(),
-                        enabled: state.is_enabled(toggle.name, &unleash_context),
-                        variant: EvaluatedVariant {
-                            name: variant.name,
-                            enabled: variant.enabled,
-                            payload: variant.payload.map(|succ| Payload {
-                                payload_type: succ.payload_type,
-                                value: succ.value,
-                            }),
-                        },
-                        impression_data: false,
-                    }
-                })
-                .collect();
-            FrontendResult {
-                toggles: evaluated_toggles,
-            }
+        .map(|c| (c.client_features.clone(), c.etag.clone()))
+        .ok_or(EdgeError::NoCachedFeatures)?;
+    client_features.features.retain(|feature| {
+        let matches_prefix = filters
+            .name_prefix
+            .as_ref()
+            .map(|prefix| feature.name.starts_with(prefix.as_str()))
+            .unwrap_or(true);
+        let matches_project = filters.projects.is_empty()
+            || filters.projects.contains(
+                &feature
+                    .project
+                    .clone()
+                    .unwrap_or_else(|| "default".to_string()),
+            );
+        token.has_access_to_project(feature.project.as_deref()) && matches_prefix && matches_project
+    });
+    match cached_etag {
+        Some(cached_etag) if req.query_string().is_empty() => {
+            etag::cached_json_response(&req, &cached_etag, Some(&token.token), &client_features)
+        }
+        Some(cached_etag) => etag::cached_json_response(
+            &req,
+            &etag::compute_etag(format!("{}?{}", cached_etag, req.query_string()).as_bytes()),
+            None,
+            &client_features,
+        ),
+        None => etag::json_response(&req, &client_features),
+    }
+}
+
+/// Cached features carry no tags, so tag filtering has to be done by upstream. Its answer is
+/// kept per token and query until the refresher installs new features for the token, so SDKs
+/// polling with the same tags don't each cost an upstream request
+async fn tag_filtered_features(
+    token: &EdgeToken,
+    all_tokens: &FullState,
+    tag_filter_cache: &TagFilterCache,
+    client: &reqwest::Client,
+    config: &EdgeConfig,
+    req: &HttpRequest,
+) -> EdgeResult<(String, Arc<serde_json::Value>)> {
+    let source_etag = all_tokens
+        .data
+        .get(&token.token)
+        .and_then(|c| c.etag.clone());
+    let key = (token.token.clone(), req.query_string().to_string());
+    if let Some(cached) = tag_filter_cache
+        .get(&key)
+        .filter(|cached| cached.source_etag == source_etag)
+    {
+        return Ok((cached.etag.clone(), Arc::clone(&cached.features)));
+    }
+    let mut features = fetcher::fetch_client_features(
+        client.clone(),
+        format!(
+            "{}/api/client/features?{}",
+            config.unleash_url,
+            req.query_string()
+        ),
+        token.token.clone(),
+    )
+    .await?;
+    features
+        .features
+        .retain(|feature| token.has_access_to_project(feature.project.as_deref()));
+    let features =
+        Arc::new(serde_json::to_value(&features).map_err(|_| EdgeError::CouldNotSerialize)?);
+    let etag = etag::canonical_etag(features.as_ref())?;
+    // Answers for the token's previous features won't be served again
+    tag_filter_cache.retain(|(cached_token, _), cached| {
+        *cached_token != token.token || cached.source_etag == source_etag
+    });
+    tag_filter_cache.insert(
+        key,
+        TagFilteredFeatures {
+            source_etag,
+            etag: etag.clone(),
+            features: Arc::clone(&features),
+        },
+    );
+    Ok((etag, features))
+}
+
+fn evaluate_toggle(
+    engine: &EngineState,
+    toggle_name: &str,
+    impression_data: bool,
+    unleash_context: &InnerContext,
+) -> EvaluatedToggle {
+    let variant = engine.get_variant(toggle_name.to_string(), unleash_context);
+    EvaluatedToggle {
+        name: toggle_name.to_string(),
+        enabled: engine.is_enabled(toggle_name.to_string(), unleash_context),
+        variant: EvaluatedVariant {
+            name: variant.name,
+            enabled: variant.enabled,
+            payload: variant.payload.map(|succ| Payload {
+                payload_type: succ.payload_type,
+                value: succ.value,
+            }),
+        },
+        impression_data,
+    }
+}
+
+pub(crate) fn evaluate_toggles(
+    token: &EdgeToken,
+    engines: &EngineCache,
+    unleash_context: &InnerContext,
+    requested: Option<&HashSet<String>>,
+) -> FrontendResult {
+    FrontendResult {
+        toggles: engine_snapshot(token, engines)
+            .map(|snapshot| {
+                visible_toggles(token, &snapshot, requested)
+                    .map(|toggle| {
+                        evaluate_toggle(
+                            &snapshot.engine,
+                            &toggle.name,
+                            toggle.impression_data,
+                            unleash_context,
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+/// The engine and toggle list of the token, taken together so they come from the same features
+pub(crate) fn engine_snapshot(
+    token: &EdgeToken,
+    engines: &EngineCache,
+) -> Option<Arc<EngineSnapshot>> {
+    engines
+        .get(&token.token)
+        .map(|snapshot| Arc::clone(snapshot.value()))
+}
+
+/// The toggles in the token's projects, limited to the requested names when there are any
+fn visible_toggles<'a>(
+    token: &'a EdgeToken,
+    snapshot: &'a EngineSnapshot,
+    requested: Option<&'a HashSet<String>>,
+) -> impl Iterator<Item = &'a ToggleInfo> {
+    snapshot
+        .toggles
+        .iter()
+        .filter(move |toggle| token.has_access_to_project(toggle.project.as_deref()))
+        .filter(move |toggle| {
+            requested
+                .map(|names| names.contains(&toggle.name))
+                .unwrap_or(true)
         })
-        .unwrap_or(FrontendResult { toggles: vec![] });
-    Ok(Json(res))
 }
 
-pub fn configure_proxy(cfg: &mut web::ServiceConfig) {
-    cfg.service(web::resource("/toggles").route(web::get().to(evaluate_toggles)));
+/// The Unleash proxy only returns toggles that are enabled for the given context
+fn enabled_only(result: FrontendResult) -> FrontendResult {
+    FrontendResult {
+        toggles: result
+            .toggles
+            .into_iter()
+            .filter(|toggle| toggle.enabled)
+            .collect(),
+    }
+}
+
+fn evaluate_single_toggle(
+    token: &EdgeToken,
+    feature_name: &str,
+    engines: &EngineCache,
+    unleash_context: &InnerContext,
+) -> EdgeResult<EvaluatedToggle> {
+    let snapshot = engine_snapshot(token, engines).ok_or(EdgeError::FeatureNotFound)?;
+    let impression_data = snapshot
+        .toggles
+        .iter()
+        .find(|toggle| {
+            toggle.name == feature_name && token.has_access_to_project(toggle.project.as_deref())
+        })
+        .map(|toggle| toggle.impression_data)
+        .ok_or(EdgeError::FeatureNotFound)?;
+    let evaluated = evaluate_toggle(
+        &snapshot.engine,
+        feature_name,
+        impression_data,
+        unleash_context,
+    );
+    if evaluated.enabled {
+        Ok(evaluated)
+    } else {
+        Err(EdgeError::FeatureNotFound)
+    }
+}
+
+/// The explain endpoint is only registered when it is enabled, so requests for it never reach
+/// a handler otherwise
+pub fn configure_proxy(cfg: &mut web::ServiceConfig, config: &EdgeConfig) {
+    cfg.service(
+        web::resource("/toggles")
+            .route(web::get().to(get_evaluated_toggles))
+            .route(web::post().to(post_evaluated_toggles)),
+    )
+    .service(
+        web::resource("/frontend")
+            .route(web::get().to(get_evaluated_toggles))
+            .route(web::post().to(post_evaluated_toggles)),
+    )
+    .service(
+        web::resource("/proxy")
+            .route(web::get().to(get_enabled_toggles))
+            .route(web::post().to(post_enabled_toggles)),
+    )
+    .service(
+        web::resource("/frontend/bulk")
+            .app_data(web::JsonConfig::default().limit(BULK_EVALUATION_PAYLOAD_LIMIT))
+            .route(web::post().to(post_bulk_evaluation)),
+    )
+    .service(web::resource("/frontend/stream").route(web::get().to(stream::stream_toggles)))
+    .service(web::resource("/frontend/ws").route(web::get().to(websocket::websocket_toggles)))
+    .service(
+        web::resource("/frontend/features/{feature_name}")
+            .route(web::get().to(get_evaluated_toggle))
+            .route(web::post().to(post_evaluated_toggle)),
+    )
+    .service(web::resource("/client/features").route(web::get().to(get_client_features)));
+    if config.enable_explain {
+        cfg.service(
+            web::resource("/frontend/features/{feature_name}/explain")
+                .route(web::get().to(explain::get_explanation))
+                .route(web::post().to(explain::post_explanation)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_context_fields_are_read_directly() {
+        let query = QueryData::from_query_string(
+            "userId=7&sessionId=s1&environment=dev&appName=web&currentTime=2022-12-01T10:00:00Z",
+        )
+        .unwrap();
+        assert_eq!(query.user_id.as_deref(), Some("7"));
+        assert_eq!(query.session_id.as_deref(), Some("s1"));
+        assert_eq!(query.environment.as_deref(), Some("dev"));
+        assert_eq!(query.app_name.as_deref(), Some("web"));
+        assert_eq!(query.current_time.as_deref(), Some("2022-12-01T10:00:00Z"));
+        assert!(query.properties.is_none());
+    }
+
+    #[test]
+    fn bracketed_properties_are_unwrapped() {
+        let query =
+            QueryData::from_query_string("properties[companyId]=bricks&properties[tier]=gold")
+                .unwrap();
+        let properties = query.properties.unwrap();
+        assert_eq!(
+            properties.get("companyId").map(String::as_str),
+            Some("bricks")
+        );
+        assert_eq!(properties.get("tier").map(String::as_str), Some("gold"));
+    }
+
+    #[test]
+    fn unknown_parameters_become_properties() {
+        let query = QueryData::from_query_string("userId=7&companyId=bricks").unwrap();
+        assert_eq!(query.user_id.as_deref(), Some("7"));
+        let properties = query.properties.unwrap();
+        assert_eq!(properties.len(), 1);
+        assert_eq!(
+            properties.get("companyId").map(String::as_str),
+            Some("bricks")
+        );
+    }
+
+    #[test]
+    fn remote_address_is_dropped() {
+        let query = QueryData::from_query_string("remoteAddress=10.0.0.1").unwrap();
+        assert!(query.properties.is_none());
+    }
+
+    #[test]
+    fn repeated_toggles_are_collected() {
+        let query =
+            QueryData::from_query_string("toggles=checkout&toggles=search&userId=7").unwrap();
+        assert_eq!(
+            query.toggles,
+            vec!["checkout".to_string(), "search".to_string()]
+        );
+        assert!(query.properties.is_none());
+    }
+
+    #[test]
+    fn only_a_current_time_in_the_query_counts_as_the_clients() {
+        let with_time = actix_web::test::TestRequest::with_uri(
+            "/api/frontend/stream?userId=7&currentTime=2022-12-01T10:00:00Z",
+        )
+        .to_http_request();
+        assert!(has_current_time(&with_time));
+        let without_time = actix_web::test::TestRequest::with_uri("/api/frontend/stream?userId=7")
+            .to_http_request();
+        assert!(!has_current_time(&without_time));
+    }
 }
@@ -0,0 +1,183 @@
+use actix_web::http::header::FORWARDED;
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+/// Resolves the address of the client that made the request. Forwarding headers are only
+/// followed while the hop that added them is one of the trusted proxies, so a client can not
+/// spoof its address by sending the headers itself
+pub fn resolve(req: &HttpRequest, trusted_proxies: &[IpNet]) -> Option<String> {
+    let peer = req.peer_addr()?.ip();
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    let mut client = peer;
+    if is_trusted(&client) {
+        for hop in forwarded_chain(req).iter().rev() {
+            match parse_address(hop) {
+                Some(ip) => client = ip,
+                None => break,
+            }
+            if !is_trusted(&client) {
+                break;
+            }
+        }
+    }
+    Some(client.to_string())
+}
+
+/// Addresses the request was forwarded for, oldest hop first. Prefers the standard Forwarded
+/// header over X-Forwarded-For when both are present
+fn forwarded_chain(req: &HttpRequest) -> Vec<String> {
+    let headers = req.headers();
+    let forwarded: Vec<String> = headers
+        .get_all(FORWARDED)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| value.trim().to_string())
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim().to_string())
+        .filter(|hop| !hop.is_empty())
+        .collect()
+}
+
+/// Accepts bare addresses as well as the quoted, bracketed and port-suffixed forms allowed
+/// by RFC 7239, e.g. `"[2001:db8::17]:4711"` or `192.0.2.43:47011`
+fn parse_address(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim_matches('"');
+    if let Ok(ip) = hop.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(socket) = hop.parse::<SocketAddr>() {
+        return Some(socket.ip());
+    }
+    hop.strip_prefix('[')
+        .and_then(|rest| rest.split(']').next())
+        .and_then(|ip| ip.parse::<IpAddr>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn trusted(networks: &[&str]) -> Vec<IpNet> {
+        networks
+            .iter()
+            .map(|network| network.parse().unwrap())
+            .collect()
+    }
+
+    fn request_from(peer: &str) -> TestRequest {
+        TestRequest::default().peer_addr(format!("{}:50000", peer).parse().unwrap())
+    }
+
+    #[test]
+    fn forged_header_from_untrusted_peer_is_ignored() {
+        let req = request_from("198.51.100.7")
+            .insert_header(("X-Forwarded-For", "203.0.113.5"))
+            .to_http_request();
+        assert_eq!(
+            resolve(&req, &trusted(&["10.0.0.0/8"])),
+            Some("198.51.100.7".to_string())
+        );
+    }
+
+    #[test]
+    fn headers_are_ignored_without_trusted_proxies() {
+        let req = request_from("10.0.0.1")
+            .insert_header(("X-Forwarded-For", "203.0.113.5"))
+            .to_http_request();
+        assert_eq!(resolve(&req, &[]), Some("10.0.0.1".to_string()));
+    }
+
+    #[test]
+    fn trusted_proxies_are_walked_from_the_right() {
+        let req = request_from("10.0.0.1")
+            .insert_header(("X-Forwarded-For", "192.0.2.99, 203.0.113.5, 10.0.0.2"))
+            .to_http_request();
+        assert_eq!(
+            resolve(&req, &trusted(&["10.0.0.0/8"])),
+            Some("203.0.113.5".to_string())
+        );
+    }
+
+    #[test]
+    fn walk_stops_at_the_first_untrusted_hop() {
+        let req = request_from("10.0.0.1")
+            .insert_header(("X-Forwarded-For", "192.0.2.99, 203.0.113.5"))
+            .to_http_request();
+        assert_eq!(
+            resolve(&req, &trusted(&["10.0.0.0/8"])),
+            Some("203.0.113.5".to_string())
+        );
+    }
+
+    #[test]
+    fn forwarded_takes_precedence_over_x_forwarded_for() {
+        let req = request_from("10.0.0.1")
+            .insert_header(("X-Forwarded-For", "198.51.100.7"))
+            .insert_header((FORWARDED, "for=203.0.113.5;proto=https"))
+            .to_http_request();
+        assert_eq!(
+            resolve(&req, &trusted(&["10.0.0.0/8"])),
+            Some("203.0.113.5".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_hop_stops_the_walk() {
+        let req = request_from("10.0.0.1")
+            .insert_header((FORWARDED, "for=203.0.113.5, for=unknown"))
+            .to_http_request();
+        assert_eq!(
+            resolve(&req, &trusted(&["10.0.0.0/8"])),
+            Some("10.0.0.1".to_string())
+        );
+    }
+
+    #[test]
+    fn ipv6_forms_are_parsed() {
+        let expected: IpAddr = "2001:db8::17".parse().unwrap();
+        for hop in [
+            "2001:db8::17",
+            "\"[2001:db8::17]\"",
+            "\"[2001:db8::17]:4711\"",
+            "[2001:db8::17]:4711",
+        ] {
+            assert_eq!(parse_address(hop), Some(expected), "{}", hop);
+        }
+    }
+
+    #[test]
+    fn ipv4_with_port_is_parsed() {
+        assert_eq!(
+            parse_address("192.0.2.43:47011"),
+            Some("192.0.2.43".parse().unwrap())
+        );
+        assert_eq!(parse_address("unknown"), None);
+        assert_eq!(parse_address("_hidden"), None);
+    }
+
+    #[test]
+    fn quoted_ipv6_in_forwarded_header_is_resolved() {
+        let req = request_from("10.0.0.1")
+            .insert_header((FORWARDED, "for=\"[2001:db8::17]:4711\""))
+            .to_http_request();
+        assert_eq!(
+            resolve(&req, &trusted(&["10.0.0.0/8"])),
+            Some("2001:db8::17".to_string())
+        );
+    }
+}
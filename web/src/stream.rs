@@ -0,0 +1,91 @@
+use crate::item_cache::{EngineCache, FeatureUpdates};
+use crate::proxy::{context_from_query, evaluate_toggles, has_current_time, toggles_from_query};
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::web::{Bytes, Data};
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::Utc;
+use futures::stream::{self, StreamExt};
+use sdk_core::state::InnerContext;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+use types::extractors::token_guards::FrontendToken;
+use types::{EdgeError, EdgeResult, EdgeToken};
+
+/// How long a stream may stay silent before a comment is sent, so proxies and load balancers
+/// don't close it as idle
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Server-Sent Events stream of the evaluated toggles. The current result is sent as soon as
+/// the client connects, and again every time the refresher installs new features for the token,
+/// evaluated at the current time unless the client gave a currentTime
+pub async fn stream_toggles(
+    FrontendToken(token): FrontendToken,
+    engines: Data<Arc<EngineCache>>,
+    updates: Data<FeatureUpdates>,
+    req: HttpRequest,
+) -> EdgeResult<HttpResponse> {
+    let unleash_context = context_from_query(&token, &req)?;
+    // A currentTime the client asked for is kept, otherwise updates are evaluated at their time
+    let client_time = has_current_time(&req);
+    let requested = toggles_from_query(&req);
+    let engines = engines.get_ref().clone();
+    let initial = toggles_event(&token, &engines, &unleash_context, requested.as_ref())?;
+    let next_keep_alive = Instant::now() + KEEP_ALIVE_INTERVAL;
+    let changes = stream::unfold(
+        (updates.subscribe(), next_keep_alive),
+        move |(mut receiver, mut next_keep_alive)| {
+            let token = token.clone();
+            let engines = engines.clone();
+            let mut unleash_context = unleash_context.clone();
+            let requested = requested.clone();
+            async move {
+                loop {
+                    tokio::select! {
+                        update = receiver.recv() => match update {
+                            Ok(changed) if changed != token.token => continue,
+                            // A lagging receiver may have missed an update for our token, so resend
+                            Ok(_) | Err(RecvError::Lagged(_)) => {
+                                if !client_time {
+                                    unleash_context.current_time = Some(Utc::now().to_rfc3339());
+                                }
+                                let event = toggles_event(
+                                    &token,
+                                    &engines,
+                                    &unleash_context,
+                                    requested.as_ref(),
+                                )
+                                .map_err(actix_web::Error::from);
+                                next_keep_alive = Instant::now() + KEEP_ALIVE_INTERVAL;
+                                return Some((event, (receiver, next_keep_alive)));
+                            }
+                            Err(RecvError::Closed) => return None,
+                        },
+                        _ = tokio::time::sleep_until(next_keep_alive) => {
+                            next_keep_alive = Instant::now() + KEEP_ALIVE_INTERVAL;
+                            let comment = Ok(Bytes::from_static(b": keep-alive\n\n"));
+                            return Some((comment, (receiver, next_keep_alive)));
+                        }
+                    }
+                }
+            }
+        },
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(stream::once(async { Ok(initial) }).chain(changes)))
+}
+
+fn toggles_event(
+    token: &EdgeToken,
+    engines: &EngineCache,
+    unleash_context: &InnerContext,
+    requested: Option<&HashSet<String>>,
+) -> EdgeResult<Bytes> {
+    let result = evaluate_toggles(token, engines, unleash_context, requested);
+    let data = serde_json::to_string(&result).map_err(|_| EdgeError::CouldNotSerialize)?;
+    Ok(Bytes::from(format!("event: update\ndata: {}\n\n", data)))
+}
@@ -0,0 +1,439 @@
+use crate::item_cache::{self, EngineCache, FeatureUpdates};
+use crate::EdgeConfig;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::web::Data;
+use actix_web::HttpMessage;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use futures::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use storage::{CachedData, FullState, TokenStore};
+use tracing::{info, warn};
+use types::{ApiToken, ApiTokenType, EdgeError, EdgeResult, EdgeToken};
+
+/// How long a token upstream rejected is refused without asking upstream again
+const REJECTED_TOKEN_TTL: Duration = Duration::from_secs(60);
+
+/// Most rejected tokens to remember, so garbage tokens can't grow the cache without bounds
+const MAX_REJECTED_TOKENS: usize = 10_000;
+
+/// How long the request that taught Edge a token waits for the token's features. A slower
+/// upstream is left to the refresh job
+const LEARN_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves tokens against upstream Unleash. Validated tokens are kept in the token store and
+/// rejected ones are remembered for a while, so upstream is not asked about a token every time
+/// it is seen
+pub struct TokenValidator {
+    config: EdgeConfig,
+    client: reqwest::Client,
+    token_store: Arc<dyn TokenStore + Send + Sync>,
+    rejected: DashMap<String, Instant>,
+    state: Arc<FullState>,
+    engines: Arc<EngineCache>,
+    updates: FeatureUpdates,
+    dynamic_tokens: AtomicUsize,
+}
+
+impl TokenValidator {
+    pub fn new(
+        config: &EdgeConfig,
+        client: reqwest::Client,
+        token_store: Arc<dyn TokenStore + Send + Sync>,
+        state: Arc<FullState>,
+        engines: Arc<EngineCache>,
+        updates: FeatureUpdates,
+    ) -> Self {
+        TokenValidator {
+            config: config.clone(),
+            client,
+            token_store,
+            rejected: DashMap::new(),
+            state,
+            engines,
+            updates,
+            dynamic_tokens: AtomicUsize::new(0),
+        }
+    }
+
+    /// Refuses tokens Edge isn't refreshing when it won't learn them anyway, in strict mode or
+    /// once the cap on dynamic tokens is reached, so they never cost an upstream request
+    pub fn admit(&self, token: &EdgeToken) -> EdgeResult<()> {
+        if self.state.data.contains_key(&token.token) {
+            Ok(())
+        } else if self.config.strict {
+            Err(EdgeError::AuthorizationDenied(
+                "strict mode only accepts preapproved and registered tokens".to_string(),
+            ))
+        } else if self.dynamic_tokens.load(Ordering::SeqCst) >= self.config.max_dynamic_tokens {
+            Err(self.limit_reached())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn limit_reached(&self) -> EdgeError {
+        EdgeError::AuthorizationDenied(format!(
+            "the limit of {} dynamic tokens is reached",
+            self.config.max_dynamic_tokens
+        ))
+    }
+
+    /// Returns the token with the type and projects upstream has for it, or AuthorizationDenied
+    /// when upstream doesn't know the token
+    pub async fn validate(&self, token: EdgeToken) -> EdgeResult<EdgeToken> {
+        if let Some(known) = self.token_store.get_token(token.token.clone()).await? {
+            if matches!(known.token_type, ApiToken::Decided(_)) {
+                return Ok(known);
+            }
+        }
+        let unknown_token =
+            || EdgeError::AuthorizationDenied("the token is not known upstream".to_string());
+        if self.was_rejected(&token.token) {
+            return Err(unknown_token());
+        }
+        let upstream = fetcher::validate_tokens(
+            self.client.clone(),
+            format!("{}/edge/validate", self.config.unleash_url),
+            vec![token.token.clone()],
+        )
+        .await?
+        .into_iter()
+        .find(|validated| validated.token == token.token);
+        let upstream = match upstream {
+            Some(upstream) => upstream,
+            None => {
+                self.remember_rejected(token.token);
+                return Err(unknown_token());
+            }
+        };
+        info!("Upstream validated a {:?} token", upstream.token_type);
+        let validated = EdgeToken {
+            token_type: ApiToken::Decided(upstream.token_type),
+            projects: upstream.projects,
+            ..token
+        };
+        self.token_store.add_token(validated.clone()).await?;
+        Ok(validated)
+    }
+
+    fn was_rejected(&self, token: &str) -> bool {
+        match self
+            .rejected
+            .get(token)
+            .map(|rejected_at| rejected_at.elapsed())
+        {
+            Some(age) if age < REJECTED_TOKEN_TTL => true,
+            Some(_) => {
+                self.rejected.remove(token);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn remember_rejected(&self, token: String) {
+        if self.rejected.len() >= MAX_REJECTED_TOKENS {
+            self.rejected
+                .retain(|_, rejected_at| rejected_at.elapsed() < REJECTED_TOKEN_TTL);
+        }
+        if self.rejected.len() >= MAX_REJECTED_TOKENS {
+            let oldest = self
+                .rejected
+                .iter()
+                .min_by_key(|entry| *entry.value())
+                .map(|entry| entry.key().clone());
+            if let Some(oldest) = oldest {
+                self.rejected.remove(&oldest);
+            }
+        }
+        self.rejected.insert(token, Instant::now());
+    }
+
+    /// Adds a validated client or frontend token Edge isn't refreshing yet to the refresh set,
+    /// marked as dynamic, and fetches its features before returning unless upstream is slow
+    pub async fn learn(&self, token: EdgeToken) -> EdgeResult<EdgeToken> {
+        let learnable = matches!(
+            token.token_type,
+            ApiToken::Decided(ApiTokenType::Client | ApiTokenType::Frontend)
+        );
+        if !learnable || self.state.data.contains_key(&token.token) {
+            return Ok(token);
+        }
+        let reserved = self
+            .dynamic_tokens
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < self.config.max_dynamic_tokens).then_some(count + 1)
+            })
+            .is_ok();
+        if !reserved {
+            warn!(
+                "Not learning a {} token, the limit of {} dynamic tokens is reached",
+                token.environment, self.config.max_dynamic_tokens
+            );
+            return Err(self.limit_reached());
+        }
+        match self.state.data.entry(token.token.clone()) {
+            // Another request learned the token in the meantime
+            Entry::Occupied(_) => {
+                self.dynamic_tokens.fetch_sub(1, Ordering::SeqCst);
+                return Ok(token);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(CachedData::default());
+            }
+        }
+        let learned = EdgeToken {
+            dynamic: true,
+            ..token
+        };
+        info!("Learned a dynamic token for {}", learned.environment);
+        self.token_store.add_token(learned.clone()).await?;
+        let fetch = item_cache::refresh_token(
+            &self.state,
+            &self.engines,
+            &self.updates,
+            &self.client,
+            &self.config,
+            learned.token.clone(),
+        );
+        if tokio::time::timeout(LEARN_FETCH_TIMEOUT, fetch)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Upstream did not answer within {:?}, the features of the learned {} token \
+                 will be fetched by the refresh job",
+                LEARN_FETCH_TIMEOUT, learned.environment
+            );
+        }
+        Ok(learned)
+    }
+}
+
+/// Validates the token in the Authorization header before the request reaches a handler, and
+/// hands the validated token to the EdgeToken extractor through the request extensions.
+/// Requests without a parseable token are passed on for the extractor to reject
+pub struct ValidateTokens;
+
+impl<S, B> Transform<S, ServiceRequest> for ValidateTokens
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = ValidateTokensMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ValidateTokensMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct ValidateTokensMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for ValidateTokensMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            let validator = req.app_data::<Data<Arc<TokenValidator>>>().cloned();
+            let token = req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|header| EdgeToken::try_from(header.clone()).ok());
+            if let (Some(validator), Some(token)) = (validator, token) {
+                let validated = async {
+                    validator.admit(&token)?;
+                    let validated = validator.validate(token).await?;
+                    validator.learn(validated).await
+                };
+                match validated.await {
+                    Ok(validated) => {
+                        req.extensions_mut().insert(validated);
+                    }
+                    // Answered as a response, not an error, so it passes through the outer
+                    // middleware such as CORS like the extractors' errors do
+                    Err(e) => return Ok(req.error_response(e).map_into_right_body()),
+                }
+            }
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use storage::memory::InMemoryRepository;
+    use tokio::sync::broadcast;
+
+    fn validator(configure: impl FnOnce(&mut EdgeConfig)) -> TokenValidator {
+        // Nothing listens on port 1, so every upstream request fails straight away
+        let mut config =
+            EdgeConfig::parse_from(["unleash-edge", "--unleash-url", "http://127.0.0.1:1"]);
+        configure(&mut config);
+        TokenValidator::new(
+            &config,
+            reqwest::Client::new(),
+            Arc::new(InMemoryRepository::default()),
+            Arc::new(FullState::default()),
+            Arc::new(EngineCache::new()),
+            broadcast::channel(16).0,
+        )
+    }
+
+    fn token(secret: &str, token_type: ApiToken) -> EdgeToken {
+        EdgeToken {
+            token: format!("*:development.{}", secret),
+            environment: "development".to_string(),
+            projects: vec!["*".to_string()],
+            token_type,
+            dynamic: false,
+        }
+    }
+
+    fn client_token(secret: &str) -> EdgeToken {
+        token(secret, ApiToken::Decided(ApiTokenType::Client))
+    }
+
+    fn is_denied<T>(result: EdgeResult<T>) -> bool {
+        matches!(result, Err(EdgeError::AuthorizationDenied(_)))
+    }
+
+    #[test]
+    fn tokens_edge_refreshes_are_admitted_in_strict_mode() {
+        let validator = validator(|config| config.strict = true);
+        let known = client_token("known");
+        validator
+            .state
+            .data
+            .insert(known.token.clone(), CachedData::default());
+        assert_eq!(validator.admit(&known), Ok(()));
+        assert!(is_denied(validator.admit(&client_token("unknown"))));
+    }
+
+    #[test]
+    fn unknown_tokens_are_admitted_until_the_cap_is_reached() {
+        let validator = validator(|config| config.max_dynamic_tokens = 1);
+        assert_eq!(validator.admit(&client_token("first")), Ok(()));
+        validator.dynamic_tokens.store(1, Ordering::SeqCst);
+        assert!(is_denied(validator.admit(&client_token("second"))));
+    }
+
+    #[tokio::test]
+    async fn client_and_frontend_tokens_are_learned_as_dynamic() {
+        let validator = validator(|_| {});
+        for token in [
+            client_token("client"),
+            token("frontend", ApiToken::Decided(ApiTokenType::Frontend)),
+        ] {
+            let learned = validator.learn(token.clone()).await.unwrap();
+            assert!(learned.dynamic);
+            assert!(validator.state.data.contains_key(&token.token));
+            let stored = validator.token_store.get_token(token.token).await.unwrap();
+            assert_eq!(stored, Some(learned));
+        }
+        assert_eq!(validator.dynamic_tokens.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn admin_and_undecided_tokens_are_not_learned() {
+        let validator = validator(|_| {});
+        for token in [
+            token("admin", ApiToken::Decided(ApiTokenType::Admin)),
+            token("undecided", ApiToken::Undecided),
+        ] {
+            let returned = validator.learn(token.clone()).await.unwrap();
+            assert!(!returned.dynamic);
+            assert!(!validator.state.data.contains_key(&token.token));
+        }
+        assert_eq!(validator.dynamic_tokens.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn learning_past_the_cap_is_refused() {
+        let validator = validator(|config| config.max_dynamic_tokens = 1);
+        assert!(validator.learn(client_token("first")).await.is_ok());
+        let second = client_token("second");
+        assert!(is_denied(validator.learn(second.clone()).await));
+        assert!(!validator.state.data.contains_key(&second.token));
+        assert_eq!(validator.dynamic_tokens.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn tokens_already_refreshed_are_not_learned_again() {
+        let validator = validator(|_| {});
+        let known = client_token("known");
+        validator
+            .state
+            .data
+            .insert(known.token.clone(), CachedData::default());
+        let returned = validator.learn(known).await.unwrap();
+        assert!(!returned.dynamic);
+        assert_eq!(validator.dynamic_tokens.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn rejected_tokens_are_refused_without_asking_upstream() {
+        let validator = validator(|_| {});
+        let rejected = client_token("rejected");
+        validator.remember_rejected(rejected.token.clone());
+        // Upstream is unreachable, so asking it would fail with UnleashApiError instead
+        assert!(is_denied(validator.validate(rejected).await));
+    }
+
+    #[test]
+    fn rejected_tokens_are_forgotten_after_the_ttl() {
+        let validator = validator(|_| {});
+        validator.remember_rejected("rejected".to_string());
+        assert!(validator.was_rejected("rejected"));
+        let expired = Instant::now() - REJECTED_TOKEN_TTL - Duration::from_secs(1);
+        validator.rejected.insert("rejected".to_string(), expired);
+        assert!(!validator.was_rejected("rejected"));
+        assert!(validator.rejected.is_empty());
+    }
+
+    #[test]
+    fn the_oldest_rejected_token_is_evicted_when_full() {
+        let validator = validator(|_| {});
+        let now = Instant::now();
+        for i in 0..MAX_REJECTED_TOKENS {
+            let rejected_at = now - Duration::from_secs(1) + Duration::from_micros(i as u64);
+            validator
+                .rejected
+                .insert(format!("token-{}", i), rejected_at);
+        }
+        validator.remember_rejected("newest".to_string());
+        assert_eq!(validator.rejected.len(), MAX_REJECTED_TOKENS);
+        assert!(!validator.rejected.contains_key("token-0"));
+        assert!(validator.was_rejected("newest"));
+    }
+}
@@ -0,0 +1,133 @@
+use crate::item_cache::{EngineCache, FeatureUpdates};
+use crate::proxy::{
+    context_from_body, context_from_query, evaluate_toggles, has_current_time, toggles_from_query,
+};
+use actix_web::web::{Data, Payload};
+use actix_web::{HttpRequest, HttpResponse};
+use actix_ws::{CloseCode, Message, Session};
+use futures::StreamExt;
+use sdk_core::state::InnerContext;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::debug;
+use types::extractors::token_guards::FrontendToken;
+use types::{EdgeError, EdgeResult, EdgeToken};
+
+/// WebSocket channel of evaluated toggles. The client can send a new context as a JSON text
+/// message at any time; the toggles are sent on connect, after every context message and
+/// whenever the refresher installs new features for the token
+pub async fn websocket_toggles(
+    FrontendToken(token): FrontendToken,
+    engines: Data<Arc<EngineCache>>,
+    updates: Data<FeatureUpdates>,
+    req: HttpRequest,
+    body: Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    let engines = engines.get_ref().clone();
+    let mut receiver = updates.subscribe();
+    let mut unleash_context = context_from_query(&token, &req)?;
+    // A currentTime the client asked for is kept, otherwise updates are evaluated at their time
+    let mut client_time = has_current_time(&req);
+    let requested = toggles_from_query(&req);
+    actix_web::rt::spawn(async move {
+        if send_toggles(
+            &mut session,
+            &token,
+            &engines,
+            &unleash_context,
+            requested.as_ref(),
+        )
+        .await
+        .is_err()
+        {
+            return;
+        }
+        loop {
+            let sent = tokio::select! {
+                message = messages.next() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        match serde_json::from_str::<InnerContext>(&text) {
+                            Ok(new_context) => match (
+                                new_context.current_time.is_some(),
+                                context_from_body(&token, new_context, &req),
+                            ) {
+                                (has_time, Ok(new_context)) => {
+                                    client_time = has_time;
+                                    unleash_context = new_context;
+                                    send_toggles(
+                                        &mut session,
+                                        &token,
+                                        &engines,
+                                        &unleash_context,
+                                        requested.as_ref(),
+                                    )
+                                    .await
+                                }
+                                (_, Err(e)) => {
+                                    let reason = (CloseCode::Policy, format!("{:?}", e));
+                                    let _ = session.close(Some(reason.into())).await;
+                                    return;
+                                }
+                            },
+                            Err(e) => {
+                                debug!("Ignoring websocket message that is not a context: {:?}", e);
+                                Ok(())
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Ping(bytes))) => session
+                        .pong(&bytes)
+                        .await
+                        .map_err(|_| EdgeError::ConnectionClosed),
+                    Some(Ok(Message::Close(reason))) => {
+                        let _ = session.close(reason).await;
+                        return;
+                    }
+                    Some(Ok(_)) => Ok(()),
+                    Some(Err(_)) | None => return,
+                },
+                update = receiver.recv() => match update {
+                    Ok(changed) if changed != token.token => Ok(()),
+                    Ok(_) | Err(RecvError::Lagged(_)) => {
+                        if !client_time {
+                            unleash_context.current_time = Some(chrono::Utc::now().to_rfc3339());
+                        }
+                        send_toggles(
+                            &mut session,
+                            &token,
+                            &engines,
+                            &unleash_context,
+                            requested.as_ref(),
+                        )
+                        .await
+                    }
+                    Err(RecvError::Closed) => {
+                        let _ = session.close(None).await;
+                        return;
+                    }
+                }
+            };
+            if sent.is_err() {
+                return;
+            }
+        }
+    });
+    Ok(response)
+}
+
+async fn send_toggles(
+    session: &mut Session,
+    token: &EdgeToken,
+    engines: &EngineCache,
+    unleash_context: &InnerContext,
+    requested: Option<&HashSet<String>>,
+) -> EdgeResult<()> {
+    let result = evaluate_toggles(token, engines, unleash_context, requested);
+    let text = serde_json::to_string(&result).map_err(|_| EdgeError::CouldNotSerialize)?;
+    session
+        .text(text)
+        .await
+        .map_err(|_| EdgeError::ConnectionClosed)
+}